@@ -0,0 +1,105 @@
+#![no_std]
+#![no_main]
+
+// cycle counts of the control loop hot paths on the target, printed over rtt. flash with
+// `cargo run --release --example bench`. the numbers include the loop and the black_box overhead, a few cycles each
+
+use panic_rtt_target as _;
+
+use core::hint::black_box;
+use cortex_m::peripheral::DWT;
+use cortex_m_rt::entry;
+use rtt_target::{rprintln, rtt_init_print};
+use stm32f4xx_hal::{pac, prelude::*};
+
+use common::to_controller_update;
+use config::Config;
+use encoder::{Encoder, normalizer::Normalizer};
+use foc::current_loop::{CurrentCommand, CurrentLoop, CurrentSetpoint};
+
+const ITERATIONS: u32 = 1000;
+const SIGNALS: usize = 64;
+
+fn measure(name: &str, mut f: impl FnMut(u32)) {
+    let start = DWT::cycle_count();
+    for i in 0..ITERATIONS {
+        f(i);
+    }
+    let cycles = DWT::cycle_count().wrapping_sub(start);
+    rprintln!("{}: {} cycles", name, cycles / ITERATIONS);
+}
+
+#[entry]
+fn main() -> ! {
+    rtt_init_print!();
+
+    let device = pac::Peripherals::take().unwrap();
+    let mut core = cortex_m::Peripherals::take().unwrap();
+
+    // same clocks as the firmware, so the flash wait states match
+    let rcc = device.RCC.constrain();
+    let _clocks = rcc
+        .cfgr
+        .use_hse(25.MHz())
+        .sysclk(100.MHz())
+        .hclk(100.MHz())
+        .pclk1(50.MHz())
+        .pclk2(100.MHz())
+        .require_pll48clk()
+        .freeze();
+
+    core.DCB.enable_trace();
+    core.DWT.enable_cycle_counter();
+
+    let angle = |i: u32| i as f32 * 0.01 - 5.0;
+
+    measure("libm sinf + cosf", |i| {
+        black_box((libm::sinf(black_box(angle(i))), libm::cosf(black_box(angle(i)))));
+    });
+    measure("fastmath sincos", |i| {
+        black_box(fastmath::sincos(black_box(angle(i))));
+    });
+    measure("libm atan2f", |i| {
+        black_box(libm::atan2f(black_box(angle(i)), black_box(0.7)));
+    });
+    measure("fastmath atan2", |i| {
+        black_box(fastmath::atan2(black_box(angle(i)), black_box(0.7)));
+    });
+
+    // adc counts of a slow sweep, computed up front so they aren't part of the measurement
+    let config = Config::new();
+    let mut signals = [[0.0; 8]; SIGNALS];
+    for (n, x) in signals.iter_mut().enumerate() {
+        for i in 0..8 {
+            x[i] = 2000.0 + 600.0 * libm::cosf(n as f32 * 0.1 + i as f32);
+        }
+    }
+    let normalizer = Normalizer {
+        mean: 2000.0,
+        std: 600.0 / core::f32::consts::SQRT_2,
+    };
+    let mut encoder = Encoder::new([normalizer; 8], &config);
+    measure("Encoder::calculate", |i| {
+        black_box(encoder.calculate(black_box(signals[i as usize % SIGNALS]), &config));
+    });
+
+    let mut current_loop = CurrentLoop::new(&config);
+    current_loop.set_command(CurrentCommand::Current(CurrentSetpoint {
+        d: 0.0,
+        q: 1.0,
+        angle: 0.0,
+        velocity: 100.0,
+        sample: 0,
+    }), &config);
+    let mut adc = [2048; 16];
+    adc[13] = 700; // about 20 V
+    measure("CurrentLoop::update", |i| {
+        let update = to_controller_update(black_box(&adc), None, i);
+        black_box(current_loop.update(&update));
+    });
+
+    rprintln!("done");
+    loop {
+        cortex_m::asm::nop();
+    }
+}
@@ -0,0 +1,29 @@
+use std::fs;
+use std::sync::mpsc::Sender;
+
+use common::BINCODE_CFG;
+use foc::calibration::CalibrationBlob;
+use crate::comms::ArbiterReq;
+
+pub const CALIBRATION_FILE: &str = "calibration.bin";
+
+/// fetches the finished calibration from the device and writes it to `path`
+pub fn save_calibration(path: &str, arb: &Sender<ArbiterReq>) -> Result<(), String> {
+    let blob = ArbiterReq::export_calibration(arb)
+        .map_err(|_| "no reply from device".to_string())?
+        .map_err(|e| format!("device couldn't export calibration: {:?}", e))?;
+
+    let bytes = bincode::encode_to_vec(blob, BINCODE_CFG).map_err(|e| format!("{:?}", e))?;
+    fs::write(path, bytes).map_err(|e| format!("{}", e))
+}
+
+/// sends a calibration saved by `save_calibration` to the device, which then skips the calibration sweep
+pub fn load_calibration(path: &str, arb: &Sender<ArbiterReq>) -> Result<(), String> {
+    let bytes = fs::read(path).map_err(|e| format!("{}", e))?;
+    let (blob, _): (CalibrationBlob, usize) = bincode::decode_from_slice(&bytes, BINCODE_CFG)
+        .map_err(|e| format!("{:?}", e))?;
+
+    ArbiterReq::import_calibration(blob, arb)
+        .map_err(|_| "no reply from device".to_string())?
+        .map_err(|e| format!("device rejected calibration: {:?}", e))
+}
@@ -3,11 +3,16 @@ use std::time::Duration;
 
 use std::sync::{Arc, mpsc};
 use std::sync::mpsc::{channel, Sender, Receiver};
-use std::thread::spawn;
+use std::thread::{sleep, spawn};
 use eventuals::Eventual;
 
 use rusb::{DeviceHandle, GlobalContext, open_device_with_vid_pid};
 use common::*;
+use foc::trajectory::TrajectoryStatus;
+use foc::motion::{MotionCommand, MotionError, MotionQueueStatus};
+use foc::state_machine::ControllerEvent;
+use foc::calibration::{CalibrationBlob, CalibrationError};
+use config::storage::StorageError;
 use remote_obj::prelude::*;
 
 struct DeviceReader {
@@ -146,6 +151,7 @@ pub fn new_device_pair() -> (Sender<HostToDevice>, Receiver<DeviceToHost>) {
 pub enum ArbiterReq {
     Getter(ContainerGetter, Sender<Result<ContainerValue, ()>>),
     Setter(ContainerSetter, Sender<Result<(), ()>>),
+    Request(HostToDevice, Duration, Sender<DeviceToHost>),
     Other(HostToDevice)
 }
 
@@ -164,9 +170,90 @@ impl ArbiterReq {
         sender.send(req).unwrap();
     }
 
+    // for commands which the device replies to with something other than a getter or setter reply
+    fn request(x: HostToDevice, sender: &Sender<ArbiterReq>) -> Result<DeviceToHost, ()> {
+        ArbiterReq::request_with_timeout(x, Duration::from_millis(1000), sender)
+    }
+
+    fn request_with_timeout(x: HostToDevice, timeout: Duration, sender: &Sender<ArbiterReq>) -> Result<DeviceToHost, ()> {
+        let (s, r) = channel();
+        sender.send(ArbiterReq::Request(x, timeout, s)).unwrap();
+        r.recv_timeout(timeout * 2).map_err(|_| ())
+    }
+
+    pub fn trajectory(x: HostToDevice, sender: &Sender<ArbiterReq>) -> Result<TrajectoryStatus, ()> {
+        match x {
+            HostToDevice::TrajectoryPoints(_) | HostToDevice::TrajectoryStart | HostToDevice::TrajectoryStop => {}
+            _ => unreachable!()
+        }
+        match ArbiterReq::request(x, sender)? {
+            DeviceToHost::TrajectoryStatus(r) => Ok(r),
+            _ => unreachable!()
+        }
+    }
+
+    pub fn waypoint(x: HostToDevice, sender: &Sender<ArbiterReq>) -> Result<MotionQueueStatus, ()> {
+        match x {
+            HostToDevice::QueueWaypoint(_) | HostToDevice::ClearWaypoints => {}
+            _ => unreachable!()
+        }
+        match ArbiterReq::request(x, sender)? {
+            DeviceToHost::MotionQueueStatus(r) => Ok(r),
+            _ => unreachable!()
+        }
+    }
+
+    pub fn motion(x: MotionCommand, sender: &Sender<ArbiterReq>) -> Result<Result<(), MotionError>, ()> {
+        match ArbiterReq::request(HostToDevice::Motion(x), sender)? {
+            DeviceToHost::MotionReply(r) => Ok(r),
+            _ => unreachable!()
+        }
+    }
+
+    pub fn recalibrate(sender: &Sender<ArbiterReq>) -> Result<Result<(), MotionError>, ()> {
+        match ArbiterReq::request(HostToDevice::Recalibrate, sender)? {
+            DeviceToHost::RecalibrateReply(r) => Ok(r),
+            _ => unreachable!()
+        }
+    }
+
+    pub fn manual(sender: &Sender<ArbiterReq>) -> Result<Result<(), MotionError>, ()> {
+        match ArbiterReq::request(HostToDevice::Manual, sender)? {
+            DeviceToHost::ManualReply(r) => Ok(r),
+            _ => unreachable!()
+        }
+    }
+
+    pub fn export_calibration(sender: &Sender<ArbiterReq>) -> Result<Result<CalibrationBlob, CalibrationError>, ()> {
+        match ArbiterReq::request(HostToDevice::ExportCalibration, sender)? {
+            DeviceToHost::Calibration(r) => Ok(r),
+            _ => unreachable!()
+        }
+    }
+
+    pub fn import_calibration(blob: CalibrationBlob, sender: &Sender<ArbiterReq>) -> Result<Result<(), CalibrationError>, ()> {
+        match ArbiterReq::request(HostToDevice::ImportCalibration(blob), sender)? {
+            DeviceToHost::ImportCalibrationReply(r) => Ok(r),
+            _ => unreachable!()
+        }
+    }
+
+    // erasing the config sector can take a couple of seconds
+    pub fn storage(x: HostToDevice, sender: &Sender<ArbiterReq>) -> Result<Result<(), StorageError>, ()> {
+        match x {
+            HostToDevice::SaveConfig | HostToDevice::LoadConfig | HostToDevice::FactoryReset => {}
+            _ => unreachable!()
+        }
+        match ArbiterReq::request_with_timeout(x, Duration::from_secs(5), sender)? {
+            DeviceToHost::StorageReply(r) => Ok(r),
+            _ => unreachable!()
+        }
+    }
+
     pub fn other(x: HostToDevice, sender: &Sender<ArbiterReq>) {
         match x {
-            HostToDevice::AddProbe(_) | HostToDevice::ClearProbes | HostToDevice::ProbeInterval(_) => {}
+            HostToDevice::AddProbe(_) | HostToDevice::ClearProbes | HostToDevice::ProbeInterval(_) |
+            HostToDevice::ResetTiming => {}
             _ => unreachable!()
         }
         sender.send(ArbiterReq::Other(x)).unwrap();
@@ -217,6 +304,11 @@ impl Arbiter {
                             _ => unreachable!()
                         }
                     }
+                    ArbiterReq::Request(x, request_timeout, reply) => {
+                        self.cmd_s.send(x).unwrap();
+                        let r = self.cmd_r.recv_timeout(request_timeout).unwrap();
+                        let _ = reply.send(r);
+                    }
                     ArbiterReq::Other(o) => {
                         self.cmd_s.send(o).unwrap();
                     }
@@ -226,9 +318,18 @@ impl Arbiter {
     }
 }
 
-pub fn new_interface() -> (Sender<ArbiterReq>, Receiver<DeviceToHost>) {
+pub fn new_interface() -> (Sender<ArbiterReq>, Receiver<DeviceToHost>, Receiver<ControllerEvent>) {
     let (writer_send, reader_recv) = new_device_pair();
     let (scope_send, scope_recv) = channel();
+    let (event_send, event_recv) = channel();
+
+    // the device falls back to its idle_on_disconnect behaviour if these stop arriving
+    let heartbeat_send = writer_send.clone();
+    spawn(move || {
+        while heartbeat_send.send(HostToDevice::Heartbeat).is_ok() {
+            sleep(Duration::from_millis(100));
+        }
+    });
     let (reader_recv_fwd_send, reader_recv_fwd_recv) = channel();
 
     spawn(move || {
@@ -240,6 +341,10 @@ pub fn new_interface() -> (Sender<ArbiterReq>, Receiver<DeviceToHost>) {
                 DeviceToHost::ProbeCleared => {
                     scope_send.send(d2h).unwrap();
                 }
+                DeviceToHost::Event(e) => {
+                    // the gui might not be listening
+                    let _ = event_send.send(e);
+                }
                 _ => {
                     reader_recv_fwd_send.send(d2h).unwrap();
                 }
@@ -247,7 +352,7 @@ pub fn new_interface() -> (Sender<ArbiterReq>, Receiver<DeviceToHost>) {
         };
     });
 
-    (Arbiter::start(writer_send, reader_recv_fwd_recv), scope_recv)
+    (Arbiter::start(writer_send, reader_recv_fwd_recv), scope_recv, event_recv)
 }
 
 pub struct CachedGetterSetter {
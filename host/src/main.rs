@@ -3,15 +3,21 @@ mod selector;
 mod channel_selector;
 mod scope_interface;
 mod variable_getter;
+mod trajectory;
+mod calibration;
 
 use std::time::{Duration, Instant};
 
 use std::collections::{HashMap, HashSet, VecDeque};
-use std::sync::mpsc::Sender;
+use std::sync::mpsc::{Receiver, Sender};
 use remote_obj::prelude::*;
 
 use common::{HostToDevice, ContainerGetter, Container};
-use crate::comms::{ArbiterReq, GetterSetter, new_interface};
+use config::Config;
+use foc::state_machine::ControllerEvent;
+use foc::motion::MotionCommand;
+use foc::calibration::CalibrationVerdict;
+use crate::comms::{ArbiterReq, new_interface};
 
 use eframe::egui;
 use crate::egui::{CollapsingHeader, Ui};
@@ -21,6 +27,8 @@ use crate::channel_selector::ChannelSelector;
 use crate::scope_interface::ScopeInterface;
 use crate::selector::GetterSelector;
 use crate::variable_getter::VariableGetter;
+use crate::trajectory::{smoothed_square_wave, TrajectoryStreamer};
+use crate::calibration::{load_calibration, save_calibration, CALIBRATION_FILE};
 
 use std::{thread, time};
 use std::fs::File;
@@ -33,38 +41,72 @@ struct GUI {
     scope: ScopeInterface,
 
     arb: Sender<ArbiterReq>,
+    events: Receiver<ControllerEvent>,
+    event_log: VecDeque<String>,
 
     variable_getter: VariableGetter,
 
     subsampling: u32,
     plot_time: f64,
-    pos_setpoint: GetterSetter,
+    sample_rate: f32, // of the device's motion loop, packet ids count its iterations
+    pos_setpoint: f32,
     channel_selector: ChannelSelector,
     selected_channels: HashSet<ContainerGetter>,
 
     last_frame_time: Duration,
     last_frame_auto_bounds: bool,
-    start_time: Instant,
 }
 
 impl GUI {
-    pub fn new(scope: ScopeInterface, arb: Sender<ArbiterReq>) -> Self {
+    pub fn new(scope: ScopeInterface, arb: Sender<ArbiterReq>, events: Receiver<ControllerEvent>) -> Self {
         ArbiterReq::other(HostToDevice::ClearProbes, &arb);
-        let mut variable_getter = VariableGetter::new(arb.clone());
+        let variable_getter = VariableGetter::new(arb.clone());
+        let default = Config::new();
+        let control_frequency = ArbiterReq::get(getter!(Container.config.control_frequency), &arb)
+            .unwrap_or(default.control_frequency);
+        let motion_divider = ArbiterReq::get(getter!(Container.config.motion_divider), &arb)
+            .unwrap_or(default.motion_divider as f32);
         GUI {
             lines: HashMap::new(),
             lines_history: HashMap::new(),
             scope,
             arb: arb.clone(),
+            events,
+            event_log: VecDeque::new(),
             subsampling: 1,
             plot_time: 2.0,
-            pos_setpoint: variable_getter.get_getter_setter(getter!(Container.controller.voltage_controller::Foc.pos_controller.pos_setpoint)),
+            sample_rate: control_frequency / motion_divider.max(1.0),
+            pos_setpoint: 0.0,
             variable_getter,
             channel_selector: ChannelSelector::new(),
             selected_channels: HashSet::new(),
             last_frame_time: Duration::ZERO,
             last_frame_auto_bounds: false,
-            start_time: Instant::now()
+        }
+    }
+
+    pub fn motion(&mut self, command: MotionCommand) {
+        match ArbiterReq::motion(command, &self.arb) {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => self.log(format!("{:?} rejected: {:?}", command, e)),
+            Err(_) => self.log(format!("{:?} failed to send", command)),
+        }
+    }
+
+    // config storage needs the motor to be disabled
+    pub fn storage(&mut self, x: HostToDevice) {
+        let name = format!("{:?}", x);
+        match ArbiterReq::storage(x, &self.arb) {
+            Ok(Ok(())) => self.log(format!("{} done", name)),
+            Ok(Err(e)) => self.log(format!("{} failed: {:?}", name, e)),
+            Err(_) => self.log(format!("{} failed to send", name)),
+        }
+    }
+
+    fn log(&mut self, message: String) {
+        self.event_log.push_back(message);
+        if self.event_log.len() > 10 {
+            self.event_log.pop_front();
         }
     }
 
@@ -87,7 +129,7 @@ impl GUI {
         // we got some packets this frame, truncate to the given number of seconds
         if let Some(last_id) = last_id {
             self.lines.iter_mut().map(|(_, v) | {
-                let idx = v.partition_point(|&(id, _)| (id + (8000.0 * self.plot_time) as u32) < last_id);
+                let idx = v.partition_point(|&(id, _)| (id + (self.sample_rate as f64 * self.plot_time) as u32) < last_id);
                 v.drain(..idx)
             }).for_each(drop);
         }
@@ -102,16 +144,84 @@ impl eframe::App for GUI {
         egui::SidePanel::left("left panel").show(ctx, |mut ui| {
             ui.add(egui::Slider::new(&mut self.plot_time, 0.0..=60.0).text("max scope time"));
 
-            if let Some(pos_setpoint) = self.pos_setpoint.getter_setter() {
-                let slider = egui::Slider::from_get_set(-100.0..=0.0, pos_setpoint).text("Pos setpoint").smart_aim(false);
-                ui.add(slider);
+            let slider = egui::Slider::new(&mut self.pos_setpoint, -100.0..=0.0).text("Pos setpoint").smart_aim(false);
+            if ui.add(slider).changed() {
+                self.motion(MotionCommand::Position(self.pos_setpoint));
             }
+            ui.horizontal(|ui| {
+                if ui.button("Enable").clicked() {
+                    self.motion(MotionCommand::Enable(true));
+                }
+                if ui.button("Disable").clicked() {
+                    self.motion(MotionCommand::Enable(false));
+                }
+                if ui.button("Stop").clicked() {
+                    self.motion(MotionCommand::Stop);
+                }
+                if ui.button("Recalibrate").clicked() {
+                    match ArbiterReq::recalibrate(&self.arb) {
+                        Ok(Ok(())) => {}
+                        Ok(Err(e)) => self.log(format!("recalibration rejected: {:?}", e)),
+                        Err(_) => self.log(format!("recalibration failed to send")),
+                    }
+                }
+                // once `ControllerEvent::ManualStarted` is logged the output is set through the remote tree, see
+                // `ManualController`. recalibrate to leave
+                if ui.button("Manual").clicked() {
+                    match ArbiterReq::manual(&self.arb) {
+                        Ok(Ok(())) => {}
+                        Ok(Err(e)) => self.log(format!("manual mode rejected: {:?}", e)),
+                        Err(_) => self.log(format!("manual mode failed to send")),
+                    }
+                }
+                if ui.button("Save config").clicked() {
+                    self.storage(HostToDevice::SaveConfig);
+                }
+                if ui.button("Load config").clicked() {
+                    self.storage(HostToDevice::LoadConfig);
+                }
+                if ui.button("Factory reset").clicked() {
+                    self.storage(HostToDevice::FactoryReset);
+                }
+                if ui.button("Reset timing").clicked() {
+                    ArbiterReq::other(HostToDevice::ResetTiming, &self.arb);
+                }
+                if ui.button("Save calibration").clicked() {
+                    match save_calibration(CALIBRATION_FILE, &self.arb) {
+                        Ok(()) => self.log(format!("calibration saved to {}", CALIBRATION_FILE)),
+                        Err(e) => self.log(e),
+                    }
+                }
+                // only for the actuator it was saved from, the device still rejects it if it can't find the
+                // absolute position, see `ControllerEvent::CalibrationImportRejected`
+                if ui.button("Load calibration").clicked() {
+                    match load_calibration(CALIBRATION_FILE, &self.arb) {
+                        Ok(()) => self.log(format!("calibration loaded from {}", CALIBRATION_FILE)),
+                        Err(e) => self.log(e),
+                    }
+                }
+            });
 
             let selected_channels = self.channel_selector.ui(&mut ui);
             self.scope.req_set(selected_channels.clone());
             self.selected_channels = selected_channels;
             self.recv();
             ui.label(format!("frame processed in {:?}", last_frame_time));
+
+            while let Ok(event) = self.events.try_recv() {
+                match event {
+                    ControllerEvent::CalibrationReport(q) => {
+                        let result = if q.verdict == CalibrationVerdict::Pass { "passed" } else { "FAILED" };
+                        self.log(format!("calibration {} ({:?}): {} samples, std {:.3} mm, hysteresis {:.3} mm, \
+                            max deviation {:.3} mm, travel ratio {:.3}, pitch ratio {:.3}", result, q.verdict, q.samples,
+                            q.std, q.hysteresis, q.max_deviation, q.travel_ratio, q.pitch_ratio));
+                    }
+                    event => self.log(format!("{:?}", event)),
+                }
+            }
+            for event in self.event_log.iter() {
+                ui.label(event);
+            }
         });
 
         fn draw_panel(ui: &mut Ui, getter_str: &str, new_section: &str, scope_selectors: &mut Vec<GetterSelector>, variable_getter: &mut VariableGetter) {
@@ -215,7 +325,7 @@ impl eframe::App for GUI {
                     if self.selected_channels.clone().contains(&getter) {
                         let name = format!("{}", getter);
                         let line = Line::new(PlotPoints::from_iter(
-                            values.iter().map(|(id, value)| [*id as f64 / 8000.0, *value as f64])
+                            values.iter().map(|(id, value)| [*id as f64 / self.sample_rate as f64, *value as f64])
                         )).name(name);
                         plot_ui.line(line);
                     }
@@ -228,16 +338,6 @@ impl eframe::App for GUI {
         self.variable_getter.update();
 
         self.last_frame_time = Instant::now() - start;
-
-        if let Some(mut pos_setpoint) = self.pos_setpoint.getter_setter() {
-            let time = self.start_time.elapsed().as_secs();
-
-            if time % 2 == 0 {
-                pos_setpoint(Some(20.0));
-            } else {
-                pos_setpoint(Some(-100.0));
-            }
-        }
     }
 }
 
@@ -303,10 +403,17 @@ impl GUI {
 }
 
 fn main() {
-    let (arb, scope ) = new_interface();
+    let (arb, scope, events) = new_interface();
     let scope_interface = ScopeInterface::new(arb.clone(), scope);
 
-    let mut plotter = GUI::new(scope_interface, arb);
+    // test trajectory, moving between two positions every second
+    let streamer_arb = arb.clone();
+    thread::spawn(move || {
+        let generator = smoothed_square_wave(20.0, -100.0, 1.0, 0.3);
+        TrajectoryStreamer::new(streamer_arb, 0.01, generator).run()
+    });
+
+    let mut plotter = GUI::new(scope_interface, arb, events);
     // plotter.save_data();
 
     plotter.set_subsampling(1);
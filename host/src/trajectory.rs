@@ -0,0 +1,96 @@
+use std::f32::consts::PI;
+use std::sync::mpsc::Sender;
+use std::thread::sleep;
+use std::time::Duration;
+
+use common::HostToDevice;
+use foc::motion::MotionCommand;
+use foc::trajectory::{TrajectoryChunk, TrajectoryPoint, TrajectoryState, TRAJECTORY_CHUNK};
+use crate::comms::ArbiterReq;
+
+/// keeps the trajectory buffer on the device topped up with points from `generator`, which is called with the time
+/// of each point in seconds. points are `interval` seconds apart, and the device interpolates between them
+pub struct TrajectoryStreamer<F: FnMut(f64) -> TrajectoryPoint> {
+    arb: Sender<ArbiterReq>,
+    generator: F,
+    interval: f32,
+    next_point: u64, // the time is worked out from this, so it doesn't drift from adding up intervals
+}
+
+impl<F: FnMut(f64) -> TrajectoryPoint> TrajectoryStreamer<F> {
+    pub fn new(arb: Sender<ArbiterReq>, interval: f32, generator: F) -> Self {
+        TrajectoryStreamer {
+            arb,
+            generator,
+            interval,
+            next_point: 0,
+        }
+    }
+
+    fn next_chunk(&mut self) -> TrajectoryChunk {
+        let mut points = [TrajectoryPoint::default(); TRAJECTORY_CHUNK];
+        for p in points.iter_mut() {
+            *p = (self.generator)(self.next_point as f64 * self.interval as f64);
+            p.duration = self.interval;
+            self.next_point += 1;
+        }
+
+        TrajectoryChunk {
+            len: TRAJECTORY_CHUNK as u8,
+            points,
+            last: false,
+        }
+    }
+
+    /// streams until the trajectory stops running on the device
+    pub fn run(mut self) -> Result<(), ()> {
+        let chunk_time = Duration::from_secs_f32(self.interval * TRAJECTORY_CHUNK as f32);
+
+        ArbiterReq::trajectory(HostToDevice::TrajectoryStop, &self.arb)?;
+        self.next_point = 0;
+        let start = (self.generator)(0.0).position;
+
+        let mut started = false;
+        let mut chunk = self.next_chunk();
+        loop {
+            let status = ArbiterReq::trajectory(HostToDevice::TrajectoryPoints(chunk.clone()), &self.arb)?;
+            if status.accepted {
+                chunk = self.next_chunk();
+            } else {
+                sleep(chunk_time);
+            }
+
+            if !started {
+                // start once the buffer is half full, so there's some margin for usb latency
+                if status.level >= status.capacity / 2 {
+                    started = ArbiterReq::trajectory(HostToDevice::TrajectoryStart, &self.arb)?.accepted;
+                    if !started {
+                        // the device only starts a trajectory from close to its first point. this is rejected the same
+                        // way as the start while the encoder is still being calibrated
+                        let _ = ArbiterReq::motion(MotionCommand::Position(start), &self.arb)?;
+                        sleep(Duration::from_secs(1));
+                    }
+                }
+            } else if status.state != TrajectoryState::Running {
+                println!("trajectory stopped: {:?}", status);
+                return Err(());
+            }
+        }
+    }
+}
+
+/// switches between `high` and `low` every `period` seconds, with a cosine shaped move lasting `transition` seconds
+pub fn smoothed_square_wave(high: f32, low: f32, period: f32, transition: f32) -> impl FnMut(f64) -> TrajectoryPoint {
+    move |time| {
+        let cycle = (time / period as f64) as u64;
+        let (from, to) = if cycle % 2 == 0 { (low, high) } else { (high, low) };
+        let u = ((time - cycle as f64 * period as f64) as f32 / transition).min(1.0);
+
+        TrajectoryPoint {
+            duration: 0.0,
+            position: from + (to - from) * (1.0 - (PI * u).cos()) / 2.0,
+            velocity: (to - from) * PI / (2.0 * transition) * (PI * u).sin(),
+            force: 0.0,
+        }
+    }
+}
@@ -1,12 +1,21 @@
 #![no_std]
+
+pub mod timing;
+
 use bincode::{Decode, Encode};
 use bincode::config::{Configuration, LittleEndian, NoLimit, SkipFixedArrayLength, Varint};
 use bincode::de::Decoder;
 use bincode::enc::Encoder;
 use bincode::error::{DecodeError, EncodeError};
-use foc::state_machine::ControllerUpdate;
+use foc::state_machine::{ControllerEvent, ControllerUpdate};
 use config::Config;
+use config::storage::StorageError;
 use foc::transforms::PhaseCurrents;
+use foc::current_loop::CurrentLoop;
+use timing::LoopTiming;
+use foc::trajectory::{TrajectoryChunk, TrajectoryStatus};
+use foc::calibration::{CalibrationBlob, CalibrationError};
+use foc::motion::{MotionCommand, MotionError, MotionQueueStatus, QueuedWaypoint};
 use encoder::{EncoderOutput, EncoderState};
 use remote_obj::*;
 use heapless::Vec;
@@ -110,7 +119,16 @@ pub enum DeviceToHost {
     GetterReply(Result<CValue, ()>),
     ProbeAdded,
     ProbeRemoved,
-    ProbeCleared
+    ProbeCleared,
+    TrajectoryStatus(TrajectoryStatus),
+    MotionQueueStatus(MotionQueueStatus),
+    MotionReply(Result<(), MotionError>),
+    RecalibrateReply(Result<(), MotionError>),
+    ManualReply(Result<(), MotionError>),
+    Calibration(Result<CalibrationBlob, CalibrationError>),
+    ImportCalibrationReply(Result<(), CalibrationError>),
+    StorageReply(Result<(), StorageError>),
+    Event(ControllerEvent)
 }
 
 #[derive(Encode, Decode, Debug, Clone)]
@@ -120,7 +138,24 @@ pub enum HostToDevice {
     ClearProbes,
     ProbeInterval(u32),
     Setter(CSetter),
-    Getter(CGetter)
+    Getter(CGetter),
+    Heartbeat,
+    TrajectoryPoints(TrajectoryChunk),
+    TrajectoryStart,
+    TrajectoryStop,
+    QueueWaypoint(QueuedWaypoint),
+    ClearWaypoints,
+    Motion(MotionCommand),
+    Recalibrate,
+    /// hand the output stage to the host, left again with `Recalibrate`
+    Manual,
+    ExportCalibration,
+    ImportCalibration(CalibrationBlob),
+    SaveConfig,
+    LoadConfig,
+    FactoryReset,
+    /// starts the min and max of `Container::timing` and the overrun counts over
+    ResetTiming
 }
 
 #[derive(RemoteGetter, RemoteSetter, Debug)]
@@ -131,11 +166,16 @@ pub struct Container<'a> {
     #[remote(read_only)]
     pub pwm: &'a [u16; 3],
     pub controller: &'a mut foc::state_machine::Controller,
+    // copy from when the motion loop last handed it a command
+    #[remote(read_only)]
+    pub current_loop: &'a CurrentLoop,
     #[remote(read_only)]
     pub update: &'a ControllerUpdate,
     #[remote(read_only)]
     pub encoder: &'a EncoderState,
     pub config: &'a mut Config,
+    #[remote(read_only)]
+    pub timing: &'a LoopTiming,
 }
 
 type CGetter = <Container<'static> as RemoteGet>::GetterType;
@@ -143,7 +183,7 @@ type CValue = <Container<'static> as RemoteGet>::ValueType;
 
 type CSetter = <Container<'static> as RemoteSet>::SetterType;
 
-pub fn to_controller_update(adc_buf: &[u16; 16], position: Option<EncoderOutput>, config: &Config) -> ControllerUpdate {
+pub fn to_controller_update(adc_buf: &[u16; 16], position: Option<EncoderOutput>, sample: u32) -> ControllerUpdate {
     fn adc_to_voltage(adc: u16) -> f32 {
         adc as f32 / 4096.0 * 3.3
     }
@@ -165,6 +205,8 @@ pub fn to_controller_update(adc_buf: &[u16; 16], position: Option<EncoderOutput>
             w: adc_to_current(adc_buf[12] as i16 - adc_buf[9] as i16),
         }.normalize(),
         bus_voltage: vbus,
-        position: position.map(|x| x.multiply(config.encoder_len_per_cycle / core::f32::consts::TAU))
+        position,
+        sample,
+        fault: None,
     }
 }
@@ -0,0 +1,63 @@
+// how long each part of the control loops takes, in core clock cycles from the DWT cycle counter. stages are measured
+// wall clock, so a stage also counts any higher priority task that interrupted it
+use bincode::{Decode, Encode};
+use remote_obj::*;
+
+#[derive(RemoteGetter, RemoteSetter, Debug, Clone, Copy)]
+#[remote(derive(Encode, Decode, Debug))]
+pub struct StageTiming {
+    pub last: u32,
+    pub min: u32,
+    pub max: u32,
+    pub avg: f32, // exponential, over about the last 256 iterations
+}
+
+impl StageTiming {
+    pub fn new() -> StageTiming {
+        StageTiming {
+            last: 0,
+            min: u32::MAX,
+            max: 0,
+            avg: 0.0,
+        }
+    }
+
+    pub fn record(&mut self, cycles: u32) {
+        if self.min == u32::MAX {
+            self.avg = cycles as f32;
+        }
+        self.last = cycles;
+        self.min = self.min.min(cycles);
+        self.max = self.max.max(cycles);
+        self.avg += (cycles as f32 - self.avg) / 256.0;
+    }
+}
+
+#[derive(RemoteGetter, RemoteSetter, Debug, Clone, Copy)]
+#[remote(derive(Encode, Decode, Debug))]
+pub struct LoopTiming {
+    pub current: StageTiming, // the whole current loop, mostly the current controller and SVM
+    pub svm: StageTiming, // part of `current`
+    pub encoder: StageTiming,
+    pub controller: StageTiming, // the state machine and handing the command to the current loop
+    pub comms: StageTiming,
+    pub motion: StageTiming, // the whole motion loop
+    // iterations dropped because the previous one hadn't finished yet
+    pub current_overruns: u32,
+    pub motion_overruns: u32,
+}
+
+impl LoopTiming {
+    pub fn new() -> LoopTiming {
+        LoopTiming {
+            current: StageTiming::new(),
+            svm: StageTiming::new(),
+            encoder: StageTiming::new(),
+            controller: StageTiming::new(),
+            comms: StageTiming::new(),
+            motion: StageTiming::new(),
+            current_overruns: 0,
+            motion_overruns: 0,
+        }
+    }
+}
@@ -1,17 +1,163 @@
 use remote_obj::*;
 use bincode::{Encode, Decode};
+use core::f32::consts::{SQRT_2, TAU};
 
-#[derive(RemoteSetter, RemoteGetter, Debug)]
+/// bins per period of the position track in `Config::linearity_lut`
+pub const LINEARITY_BINS: usize = 32;
+/// range of `Config::control_frequency`, the ADC scan and the current loop have to fit in one period
+pub const MIN_CONTROL_FREQUENCY: f32 = 1e3;
+pub const MAX_CONTROL_FREQUENCY: f32 = 40e3;
+/// largest `Config::motion_divider`, comms run in the motion loop and stop keeping up past this
+pub const MAX_MOTION_DIVIDER: u32 = 16;
+/// largest `Ellipse::skew` either way, the correction divides by sqrt(1 - skew^2)
+pub const MAX_SKEW: f32 = 0.9;
+
+/// what the output stage does when the motor is not being actively driven
+#[derive(RemoteSetter, RemoteGetter, Debug, Clone, Copy, PartialEq, Encode, Decode)]
+#[remote(derive(Encode, Decode, Debug))]
+pub enum IdleMode {
+    /// all switches off, phases are high impedance
+    Coast,
+    /// low side switches on, shorting the phases together
+    Brake,
+    /// keep the position loop running at `hold_curr_limit`, falls back to `Brake` if that's not possible
+    Hold,
+}
+
+/// which version of the encoder signals a track's angle is computed from
+#[derive(RemoteSetter, RemoteGetter, Debug, Clone, Copy, PartialEq, Encode, Decode)]
+#[remote(derive(Encode, Decode, Debug))]
+pub enum SignalSource {
+    /// adc counts, offsets and gains are not removed
+    Raw,
+    /// offset and gain corrected by the normalizers
+    Normalized,
+    /// normalized, then with the crosstalk removed by `comp_matrix` and `comp_bias`
+    Compensated,
+}
+
+/// whether the encoder normalizers keep adapting after calibration
+#[derive(RemoteSetter, RemoteGetter, Debug, Clone, Copy, PartialEq, Encode, Decode)]
+#[remote(derive(Encode, Decode, Debug))]
+pub enum NormalizerMode {
+    Fixed,
+    /// follow drift of the sensor offsets and gains while moving
+    Adaptive,
+}
+
+/// what the open loop drive used by the calibration keeps constant while it rotates the field
+#[derive(RemoteSetter, RemoteGetter, Debug, Clone, Copy, PartialEq, Encode, Decode)]
+#[remote(derive(Encode, Decode, Debug))]
+pub enum OpenLoopMode {
+    /// `open_loop_voltage`, so the current and force depend on the coil resistance and bus voltage
+    Voltage,
+    /// `open_loop_current` through the current controller, the same force whatever the coil
+    Current,
+}
+
+/// an input to a track's angle, indices are into the encoder channels
+#[derive(RemoteSetter, RemoteGetter, Debug, Clone, Copy, PartialEq, Encode, Decode)]
+#[remote(derive(Encode, Decode, Debug))]
+pub enum Signal {
+    Single(u8),
+    /// first minus second, for coils wound in opposite directions
+    Difference([u8; 2]),
+}
+
+/// offset, amplitude and quadrature correction of a track's sin and cos, fitted during calibration
+#[derive(RemoteSetter, RemoteGetter, Debug, Clone, Copy, PartialEq, Encode, Decode)]
+#[remote(derive(Encode, Decode, Debug))]
+pub struct Ellipse {
+    pub sin_offset: f32,
+    pub cos_offset: f32,
+    pub sin_amplitude: f32,
+    pub cos_amplitude: f32,
+    pub skew: f32, // sine of how far sin is from being in quadrature with cos, at most `MAX_SKEW`
+}
+
+impl Ellipse {
+    /// leaves the signals unchanged
+    pub fn new() -> Self {
+        Ellipse::circle(1.0)
+    }
+
+    /// only scales the signals, so a circle of `radius` ends up with a radius of 1
+    pub fn circle(radius: f32) -> Self {
+        Ellipse {
+            sin_offset: 0.0,
+            cos_offset: 0.0,
+            sin_amplitude: radius,
+            cos_amplitude: radius,
+            skew: 0.0,
+        }
+    }
+}
+
+/// one sinusoidal encoder track, angle = sign * atan2(sin, cos)
+#[derive(RemoteSetter, RemoteGetter, Debug, Clone, Copy, PartialEq, Encode, Decode)]
+#[remote(derive(Encode, Decode, Debug))]
+pub struct Track {
+    pub sin: Signal,
+    pub cos: Signal,
+    pub sign: f32, // 1 or -1, so the angle increases towards positive positions
+    pub period: f32, // mm per cycle, 0 if unknown
+    pub offset: f32, // angle in cycles at position 0, for absolute position
+    pub source: SignalSource,
+    pub ellipse: Ellipse,
+}
+
+/// the field of a config that `Config::validate` rejected
+#[derive(Encode, Decode, Debug, Clone, Copy, PartialEq)]
+pub enum ConfigError {
+    ControlFrequency,
+    MotionDivider,
+    VelocityFilterFrequency,
+    /// index of the track with an ellipse skew outside `MAX_SKEW`
+    Skew(u8),
+}
+
+#[derive(RemoteSetter, RemoteGetter, Debug, Clone, Encode, Decode)]
 #[remote(derive(Encode, Decode, Debug))]
 pub struct Config {
     // setup constants
     pub motor_len_per_cycle: f32, // mm per electrical cycle
-    pub encoder_len_per_cycle: f32, // mm per cycle
+    pub force_constant: f32, // in newtons per amp of q current, negative as positive q pushes towards negative positions
+    pub encoder_adc: [u8; 8], // adc buffer index of each encoder channel
+    pub tracks: [Track; 4],
+    pub position_track: u8, // index into `tracks` of the track used for position
+    // absolute position at power up from tracks of different periods, centered on the middle of the travel
+    pub vernier_range: f32, // in mm, at most the combined period of the tracks, 0 to disable
+    pub vernier_tolerance: f32, // in cycles, how far each track can be from the decoded position
+    pub normalizer_mode: NormalizerMode,
+    pub adapt_length: f32, // in mm, distance the adaptive normalizers average over
+    pub adapt_min_velocity: f32, // in mm/s, adaptation stops below this
+    pub adapt_max_rate: f32, // in stds per second, fastest the normalizers can change
+    pub velocity_filter_frequency: f32, // in Hz, cutoff of the position filter the velocity is taken from
+    // encoder health, a failure is a fault while the position loop is running
+    pub health_min_radius: f32, // of the ellipse corrected sin and cos, 1 for a healthy track
+    pub health_max_radius: f32,
+    pub health_max_step: f32, // in cycles per sample
+    pub health_max_disagreement: f32, // in cycles, between a track and the position track
 
     // encoder calibration
     pub calibration_length: f32, // in mm
     pub calibration_speed: f32, // in electrical revolutions per second
+    pub open_loop_mode: OpenLoopMode,
     pub open_loop_voltage: f32, // in volts
+    pub open_loop_current: f32, // in amps
+    // calibrations outside these limits are rejected, errors are between the encoder and open loop positions
+    pub cal_min_samples: u32, // per direction
+    pub cal_max_std: f32, // in mm
+    pub cal_max_hysteresis: f32, // in mm, between the two directions
+    pub cal_max_deviation: f32, // in mm, from the calibrated offset
+    pub cal_max_travel_error: f32, // fraction of calibration_length the encoder travel can be off by
+    // before the sweep the open loop drive goes back and forth this many electrical cycles, to check the phase order,
+    // encoder direction and `motor_len_per_cycle` against the encoder. 0 to skip
+    pub cal_pitch_cycles: f32,
+    pub cal_max_pitch_error: f32, // fraction the encoder travel per electrical cycle can be off by
+    // manual output for board bring-up
+    pub manual_max_duty: f32, // largest voltage vector or difference between phase duties, as a fraction of vbus
+    pub manual_max_time: f32, // in seconds, the output turns itself off this long after being set
 
     pub uvlo: f32, // in volts
 
@@ -19,44 +165,160 @@ pub struct Config {
     pub switching_clock_frequency: f32,
     /// how much to switch all 3 phases to all-on for bootstrap cap recharge
     pub cycle_deadtime: f32, // in seconds
-    pub control_frequency: f32,
+    // everything below given in continuous time is discretized from these through `sample_time` for the current loop
+    // and `motion_sample_time` for everything else
+    pub control_frequency: f32, // in Hz, of the current loop, also sets the ADC trigger timer at power up
+    pub motion_divider: u32, // the encoder and motion loop run once every this many current loop iterations
+
+    // from the position being sampled to the new voltage being applied, the electrical angle for the output voltage
+    // is extrapolated over this
+    pub angle_delay: f32, // in seconds
 
     pub current_controller_k_p: f32,
-    pub current_controller_k_i: f32,
+    pub current_controller_k_i: f32, // per second
 
     pub vel_controller_k_p: f32,
-    pub vel_controller_k_i: f32,
+    pub vel_controller_k_i: f32, // per second
     pub pos_controller_k_p: f32,
 
     pub curr_limit: f32,
     pub hard_curr_limit: f32,
 
+    // limits for motion commands
+    pub position_min: f32, // in mm
+    pub position_max: f32, // in mm
+    pub max_velocity: f32, // in mm/s
+    pub enable_ramp_time: f32, // in seconds, time for the current limit to ramp from 0 to curr_limit
+
+    // idle behaviour
+    pub idle_on_fault: IdleMode, // for encoder faults, undervoltage and overcurrent always turn the driver off
+    pub idle_on_disconnect: IdleMode,
+    pub idle_on_move_done: IdleMode,
+    pub hold_curr_limit: f32, // in amps
+    pub host_timeout: f32, // in seconds
+    pub settle_tolerance: f32, // in mm
+    pub settle_time: f32, // in seconds
+    pub trajectory_start_tolerance: f32, // in mm, how far the first point of a trajectory can be from the position
+    pub waypoint_timeout: f32, // in seconds, for a MoveTo waypoint to settle once its profile has arrived
+    // in seconds, the position loop gets this long to stop the motor for a recalibration or manual mode. after that the
+    // output is disabled, and it takes over once the output is enabled again
+    pub stop_timeout: f32,
+
     pub comp_matrix: [[f32; 8]; 8],
-    pub comp_bias: [f32; 8]
+    pub comp_bias: [f32; 8],
+    // in mm, subtracted from the position depending on where it is within a period of the position track
+    pub linearity_lut: [f32; LINEARITY_BINS],
 }
 
 impl Config {
     pub fn new() -> Self {
         Config {
             motor_len_per_cycle: 19.0,
-            encoder_len_per_cycle: 2.34375,
+            force_constant: -1.0,
+            encoder_adc: [1, 2, 3, 4, 5, 6, 7, 8],
+            // normalized like before the source was selectable. the default `comp_matrix` was fitted on a single board,
+            // so a track is only switched to compensated after the calibration sweep has fitted the board it runs on
+            tracks: [
+                Track {
+                    sin: Signal::Difference([0, 1]),
+                    cos: Signal::Difference([2, 3]),
+                    sign: 1.0,
+                    period: 2.34375,
+                    offset: 0.0,
+                    source: SignalSource::Normalized,
+                    ellipse: Ellipse::circle(2.0 * SQRT_2),
+                },
+                Track {
+                    sin: Signal::Single(3),
+                    cos: Signal::Single(0),
+                    sign: 1.0,
+                    period: 2.34375,
+                    offset: 0.0,
+                    source: SignalSource::Normalized,
+                    ellipse: Ellipse::circle(SQRT_2),
+                },
+                // the outer tracks have not been characterized on this board revision
+                Track {
+                    sin: Signal::Single(4),
+                    cos: Signal::Single(5),
+                    sign: 1.0,
+                    period: 0.0,
+                    offset: 0.0,
+                    source: SignalSource::Normalized,
+                    ellipse: Ellipse::circle(SQRT_2),
+                },
+                Track {
+                    sin: Signal::Single(6),
+                    cos: Signal::Single(7),
+                    sign: 1.0,
+                    period: 0.0,
+                    offset: 0.0,
+                    source: SignalSource::Normalized,
+                    ellipse: Ellipse::circle(SQRT_2),
+                },
+            ],
+            position_track: 1,
+            // needs the periods of the outer tracks
+            vernier_range: 0.0,
+            vernier_tolerance: 0.08,
+            normalizer_mode: NormalizerMode::Fixed,
+            adapt_length: 100.0,
+            adapt_min_velocity: 5.0,
+            adapt_max_rate: 0.05,
+            velocity_filter_frequency: 100.0,
+            health_min_radius: 0.5,
+            health_max_radius: 1.5,
+            health_max_step: 0.25, // unwrapping is ambiguous at 0.5
+            health_max_disagreement: 0.15,
             calibration_length: 100.0,
-            calibration_speed: 0.1,
+            calibration_speed: 4.0 / TAU, // 4 electrical rad/s
+            open_loop_mode: OpenLoopMode::Voltage,
             open_loop_voltage: 0.5,
+            open_loop_current: 2.0,
+            cal_min_samples: 1000,
+            // a quarter of an electrical cycle of error would leave no torque at all
+            cal_max_std: 1.0,
+            cal_max_hysteresis: 2.0,
+            cal_max_deviation: 3.0,
+            cal_max_travel_error: 0.2,
+            cal_pitch_cycles: 2.0,
+            cal_max_pitch_error: 0.1,
+            manual_max_duty: 0.1,
+            manual_max_time: 2.0,
             uvlo: 10.0,
             switching_frequency: 200e3,
             switching_clock_frequency: 100e6,
             cycle_deadtime: 300e-9, // ~50ns is min controllable on time
             control_frequency: 8e3,
+            // both loops at the old rate until the loop timing has been measured
+            motion_divider: 1,
+
+            angle_delay: 125e-6, // one control period
 
             current_controller_k_p: 0.22e-4,
-            current_controller_k_i: 1000.0 * 60e-3 / 8e3,
+            current_controller_k_i: 1000.0 * 60e-3,
             vel_controller_k_p: 0.1,
-            vel_controller_k_i: 10.0 / 8e3,
+            vel_controller_k_i: 10.0,
             pos_controller_k_p: 40.0,
 
             curr_limit: 22.5,
             hard_curr_limit: 35.0,
+
+            position_min: -100.0,
+            position_max: 20.0,
+            max_velocity: 1000.0,
+            enable_ramp_time: 0.2,
+
+            idle_on_fault: IdleMode::Brake,
+            idle_on_disconnect: IdleMode::Hold,
+            idle_on_move_done: IdleMode::Hold,
+            hold_curr_limit: 10.0,
+            host_timeout: 0.5,
+            settle_tolerance: 0.05,
+            settle_time: 0.2,
+            trajectory_start_tolerance: 1.0,
+            waypoint_timeout: 1.0,
+            stop_timeout: 2.0,
             comp_matrix: [
                 [ 1.1173, -0.8311,  0.2963, -0.3230,  0.0120,  0.0302,  0.0000,  0.0000],
                 [-1.0591,  0.9015, -0.3551,  0.2872, -0.0080, -0.0282,  0.0000,  0.0000],
@@ -67,7 +329,38 @@ impl Config {
                 [ 0.0000,  0.0000,  0.0000,  0.0000,  0.0084, -0.0191,  1.3382, -0.2344],
                 [ 0.0000,  0.0000,  0.0000,  0.0000, -0.0405,  0.1293, -0.2132,  1.4222]
             ],
-            comp_bias: [-0.0095, -0.0527, -0.0642, -0.0139, -0.0410, -0.0730, -0.0624, -0.0489]
+            comp_bias: [-0.0095, -0.0527, -0.0642, -0.0139, -0.0410, -0.0730, -0.0624, -0.0489],
+            linearity_lut: [0.0; LINEARITY_BINS],
+        }
+    }
+
+    /// in seconds, the length of one current loop iteration
+    pub fn sample_time(&self) -> f32 {
+        1.0 / self.control_frequency
+    }
+
+    /// in seconds, the length of one encoder and motion loop iteration
+    pub fn motion_sample_time(&self) -> f32 {
+        self.motion_divider.max(1) as f32 / self.control_frequency
+    }
+
+    /// checks the values the firmware can't run with, before a config from storage or the host is used
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if !(self.control_frequency >= MIN_CONTROL_FREQUENCY && self.control_frequency <= MAX_CONTROL_FREQUENCY) {
+            return Err(ConfigError::ControlFrequency);
+        }
+        if !(1..=MAX_MOTION_DIVIDER).contains(&self.motion_divider) {
+            return Err(ConfigError::MotionDivider);
+        }
+        // the velocity filter runs in the motion loop, its cutoff has to be below the Nyquist frequency there
+        if !(self.velocity_filter_frequency > 0.0 && self.velocity_filter_frequency < 0.5 / self.motion_sample_time()) {
+            return Err(ConfigError::VelocityFilterFrequency);
+        }
+        for (i, track) in self.tracks.iter().enumerate() {
+            if !(-MAX_SKEW..=MAX_SKEW).contains(&track.ellipse.skew) {
+                return Err(ConfigError::Skew(i as u8));
+            }
         }
+        Ok(())
     }
 }
\ No newline at end of file
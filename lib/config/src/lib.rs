@@ -1,4 +1,5 @@
 #![no_std]
 
 pub mod config;
-pub use config::Config;
\ No newline at end of file
+pub mod storage;
+pub use config::{Config, ConfigError, Ellipse, IdleMode, NormalizerMode, OpenLoopMode, Signal, SignalSource, Track, LINEARITY_BINS, MAX_SKEW};
\ No newline at end of file
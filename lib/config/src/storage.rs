@@ -0,0 +1,240 @@
+use bincode::{Encode, Decode};
+use bincode::config::{Configuration, LittleEndian, NoLimit, SkipFixedArrayLength, Varint};
+use crate::{Config, ConfigError};
+
+/// bump once per release that changes the encoded layout or the meaning of a field of `Config`, not for every change
+/// on the way there. a config saved with any other version is rejected and the defaults are used until it's saved again
+pub const CONFIG_VERSION: u16 = 1;
+/// largest stored config, including the header
+pub const CONFIG_STORAGE_LEN: usize = 1024;
+
+const MAGIC: u32 = 0x4746_434c; // "LCFG"
+const HEADER_LEN: usize = 12;
+
+static STORAGE_CFG: Configuration<LittleEndian, Varint, SkipFixedArrayLength, NoLimit> = bincode::config::standard()
+    .with_little_endian()
+    .with_variable_int_encoding()
+    .skip_fixed_array_length();
+
+#[derive(Encode, Decode, Debug, Clone, Copy, PartialEq)]
+pub enum StorageError {
+    /// the underlying storage failed to read, erase or program
+    Flash,
+    /// nothing has been saved yet
+    Empty,
+    /// bad magic, length or CRC
+    Corrupt,
+    /// saved by firmware with a layout we don't know how to read
+    UnsupportedVersion(u16),
+    /// read fine, but has a value the firmware can't run with
+    Invalid(ConfigError),
+    TooLarge,
+    /// the motor has to be disabled first, or another storage operation is in progress
+    Busy,
+}
+
+/// a region of non-volatile memory reserved for the config. like flash, `write` can only be used on erased memory
+pub trait ConfigStorage {
+    fn read(&mut self, offset: usize, buf: &mut [u8]) -> Result<(), StorageError>;
+    fn erase(&mut self) -> Result<(), StorageError>;
+    fn write(&mut self, offset: usize, data: &[u8]) -> Result<(), StorageError>;
+}
+
+/// behaves like erased flash, for testing
+pub struct MemoryStorage<const N: usize> {
+    pub data: [u8; N],
+}
+
+impl<const N: usize> MemoryStorage<N> {
+    pub fn new() -> Self {
+        MemoryStorage {
+            data: [0xff; N],
+        }
+    }
+}
+
+impl<const N: usize> ConfigStorage for MemoryStorage<N> {
+    fn read(&mut self, offset: usize, buf: &mut [u8]) -> Result<(), StorageError> {
+        let data = self.data.get(offset..offset + buf.len()).ok_or(StorageError::Flash)?;
+        buf.copy_from_slice(data);
+        Ok(())
+    }
+
+    fn erase(&mut self) -> Result<(), StorageError> {
+        self.data = [0xff; N];
+        Ok(())
+    }
+
+    fn write(&mut self, offset: usize, data: &[u8]) -> Result<(), StorageError> {
+        let target = self.data.get_mut(offset..offset + data.len()).ok_or(StorageError::Flash)?;
+        // programming can only clear bits
+        for (t, d) in target.iter_mut().zip(data.iter()) {
+            *t &= *d;
+        }
+        Ok(())
+    }
+}
+
+// CRC-32 (IEEE)
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffffu32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xedb8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+/// identifies the parts of the config an encoder calibration is only valid with, see `CalibrationBlob`
+pub fn encoder_hash(config: &Config) -> u32 {
+    let mut buf = [0u8; CONFIG_STORAGE_LEN];
+    let fields = (
+        config.motor_len_per_cycle,
+        config.encoder_adc,
+        config.tracks,
+        config.position_track,
+        config.comp_matrix,
+        config.comp_bias,
+        config.linearity_lut,
+    );
+    // always fits, it's a small part of the whole config
+    let length = bincode::encode_into_slice(fields, &mut buf, STORAGE_CFG).unwrap();
+    crc32(&buf[..length])
+}
+
+fn decode_payload(version: u16, payload: &[u8]) -> Result<Config, StorageError> {
+    if version != CONFIG_VERSION {
+        return Err(StorageError::UnsupportedVersion(version));
+    }
+    let (config, length): (Config, usize) = bincode::decode_from_slice(payload, STORAGE_CFG)
+        .map_err(|_| StorageError::Corrupt)?;
+    if length != payload.len() {
+        return Err(StorageError::Corrupt);
+    }
+    config.validate().map_err(StorageError::Invalid)?;
+    Ok(config)
+}
+
+pub fn save<S: ConfigStorage>(storage: &mut S, config: &Config) -> Result<(), StorageError> {
+    let mut buf = [0u8; CONFIG_STORAGE_LEN];
+    let length = bincode::encode_into_slice(config, &mut buf[HEADER_LEN..], STORAGE_CFG)
+        .map_err(|_| StorageError::TooLarge)?;
+
+    let crc = crc32(&buf[HEADER_LEN..HEADER_LEN + length]);
+    buf[0..4].copy_from_slice(&MAGIC.to_le_bytes());
+    buf[4..6].copy_from_slice(&CONFIG_VERSION.to_le_bytes());
+    buf[6..8].copy_from_slice(&(length as u16).to_le_bytes());
+    buf[8..12].copy_from_slice(&crc.to_le_bytes());
+
+    storage.erase()?;
+    storage.write(0, &buf[..HEADER_LEN + length])
+}
+
+pub fn load<S: ConfigStorage>(storage: &mut S) -> Result<Config, StorageError> {
+    let mut header = [0u8; HEADER_LEN];
+    storage.read(0, &mut header)?;
+
+    if header.iter().all(|x| *x == 0xff) {
+        return Err(StorageError::Empty);
+    }
+
+    let magic = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+    let version = u16::from_le_bytes([header[4], header[5]]);
+    let length = u16::from_le_bytes([header[6], header[7]]) as usize;
+    let crc = u32::from_le_bytes([header[8], header[9], header[10], header[11]]);
+
+    if magic != MAGIC || length > CONFIG_STORAGE_LEN - HEADER_LEN {
+        return Err(StorageError::Corrupt);
+    }
+
+    let mut buf = [0u8; CONFIG_STORAGE_LEN];
+    let payload = &mut buf[..length];
+    storage.read(HEADER_LEN, payload)?;
+
+    if crc32(payload) != crc {
+        return Err(StorageError::Corrupt);
+    }
+
+    decode_payload(version, payload)
+}
+
+/// erases the stored config, so the next boot uses the defaults
+pub fn factory_reset<S: ConfigStorage>(storage: &mut S) -> Result<(), StorageError> {
+    storage.erase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_crc() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let mut storage = MemoryStorage::<CONFIG_STORAGE_LEN>::new();
+        let mut config = Config::new();
+        config.curr_limit = 12.5;
+        config.comp_matrix[3][4] = 0.25;
+
+        save(&mut storage, &config).unwrap();
+        let loaded = load(&mut storage).unwrap();
+        assert_eq!(loaded.curr_limit, 12.5);
+        assert_eq!(loaded.comp_matrix, config.comp_matrix);
+
+        // saving again has to erase first, flash can't set bits
+        config.curr_limit = 7.0;
+        save(&mut storage, &config).unwrap();
+        assert_eq!(load(&mut storage).unwrap().curr_limit, 7.0);
+    }
+
+    #[test]
+    fn test_empty() {
+        let mut storage = MemoryStorage::<CONFIG_STORAGE_LEN>::new();
+        assert_eq!(load(&mut storage).err(), Some(StorageError::Empty));
+
+        save(&mut storage, &Config::new()).unwrap();
+        factory_reset(&mut storage).unwrap();
+        assert_eq!(load(&mut storage).err(), Some(StorageError::Empty));
+    }
+
+    #[test]
+    fn test_corrupt() {
+        let mut storage = MemoryStorage::<CONFIG_STORAGE_LEN>::new();
+        save(&mut storage, &Config::new()).unwrap();
+        storage.data[HEADER_LEN + 10] ^= 0x01;
+        assert_eq!(load(&mut storage).err(), Some(StorageError::Corrupt));
+
+        let mut storage = MemoryStorage::<CONFIG_STORAGE_LEN>::new();
+        save(&mut storage, &Config::new()).unwrap();
+        storage.data[6..8].copy_from_slice(&(CONFIG_STORAGE_LEN as u16).to_le_bytes());
+        assert_eq!(load(&mut storage).err(), Some(StorageError::Corrupt));
+    }
+
+    #[test]
+    fn test_unsupported_version() {
+        let mut storage = MemoryStorage::<CONFIG_STORAGE_LEN>::new();
+        save(&mut storage, &Config::new()).unwrap();
+        storage.data[4..6].copy_from_slice(&(CONFIG_VERSION + 1).to_le_bytes());
+        assert_eq!(load(&mut storage).err(), Some(StorageError::UnsupportedVersion(CONFIG_VERSION + 1)));
+    }
+
+    #[test]
+    fn test_invalid() {
+        let mut storage = MemoryStorage::<CONFIG_STORAGE_LEN>::new();
+        let mut config = Config::new();
+        config.control_frequency = 0.0;
+        save(&mut storage, &config).unwrap();
+        assert_eq!(load(&mut storage).err(), Some(StorageError::Invalid(ConfigError::ControlFrequency)));
+
+        let mut config = Config::new();
+        config.tracks[2].ellipse.skew = 1.0;
+        save(&mut storage, &config).unwrap();
+        assert_eq!(load(&mut storage).err(), Some(StorageError::Invalid(ConfigError::Skew(2))));
+    }
+}
@@ -0,0 +1,371 @@
+// least squares fit of the crosstalk compensation matrix, from samples taken while moving at a known position.
+// every channel is fitted towards an ideal sinusoid of its own period, so anything leaking in from the other tracks
+// is removed by the fit
+const HYSTERESIS: f32 = 0.5; // in units of normalized signal
+const MIN_PERIODS: u32 = 3;
+const MIN_SAMPLES: u32 = 1000;
+// channels on the same track are close to linearly dependent, so the fit needs a little regularization
+const RIDGE: f32 = 1e-4;
+
+// estimates the period from the first and last rising edge, so the hysteresis offset cancels out
+#[derive(Debug, Clone, Copy)]
+struct PeriodEstimator {
+    high: Option<bool>,
+    first: Option<f32>,
+    last: f32,
+    edges: u32,
+}
+
+impl PeriodEstimator {
+    fn new() -> PeriodEstimator {
+        PeriodEstimator {
+            high: None,
+            first: None,
+            last: 0.0,
+            edges: 0,
+        }
+    }
+
+    fn update(&mut self, value: f32, position: f32) {
+        if value > HYSTERESIS {
+            if self.high == Some(false) {
+                if self.first.is_none() {
+                    self.first = Some(position);
+                }
+                self.last = position;
+                self.edges += 1;
+            }
+            self.high = Some(true);
+        } else if value < -HYSTERESIS {
+            self.high = Some(false);
+        }
+    }
+
+    fn period(&self) -> Option<f32> {
+        if self.edges < MIN_PERIODS + 1 {
+            return None;
+        }
+        Some(libm::fabsf(self.last - self.first?) / (self.edges - 1) as f32)
+    }
+}
+
+/// mean and covariance of the normalized channels over the sweep
+#[derive(Debug, Clone, PartialEq)]
+pub struct Moments {
+    pub mean: [f32; 8],
+    pub cov: [[f32; 8]; 8],
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Compensation {
+    pub matrix: [[f32; 8]; 8], // same layout as `Config::comp_matrix`
+    pub bias: [f32; 8],
+    pub moments: Moments, // for fitting the ellipses, see `ellipse::fit_tracks`
+}
+
+#[derive(Debug, Clone)]
+pub struct CompensationFitter {
+    periods: [PeriodEstimator; 8],
+    // sums for the normal equations of the normalized channels, against the cos and sin of each channel's phase
+    x_sum: [f32; 8],
+    cos_sum: [f32; 8],
+    sin_sum: [f32; 8],
+    xtx: [[f32; 8]; 8],
+    xt_cos: [[f32; 8]; 8],
+    xt_sin: [[f32; 8]; 8],
+    samples: u32,
+}
+
+impl CompensationFitter {
+    pub fn new() -> CompensationFitter {
+        CompensationFitter {
+            periods: [PeriodEstimator::new(); 8],
+            x_sum: [0.0; 8],
+            cos_sum: [0.0; 8],
+            sin_sum: [0.0; 8],
+            xtx: [[0.0; 8]; 8],
+            xt_cos: [[0.0; 8]; 8],
+            xt_sin: [[0.0; 8]; 8],
+            samples: 0,
+        }
+    }
+
+    // in mm
+    pub fn periods(&self) -> Option<[f32; 8]> {
+        let mut periods = [0.0; 8];
+        for i in 0..8 {
+            periods[i] = self.periods[i].period()?;
+        }
+        Some(periods)
+    }
+
+    /// first pass, finds the period of each channel. `position` is in mm
+    pub fn update_periods(&mut self, normalized: &[f32; 8], position: f32) {
+        for i in 0..8 {
+            self.periods[i].update(normalized[i], position);
+        }
+    }
+
+    /// second pass over a single sweep, needs the periods from the first
+    pub fn update_fit(&mut self, normalized: &[f32; 8], position: f32) {
+        let periods = match self.periods() {
+            Some(periods) => periods,
+            None => return,
+        };
+
+        let mut cos = [0.0; 8];
+        let mut sin = [0.0; 8];
+        for i in 0..8 {
+            let phase = position / periods[i] * core::f32::consts::TAU;
+            cos[i] = libm::cosf(phase);
+            sin[i] = libm::sinf(phase);
+        }
+
+        let x = normalized;
+        for r in 0..8 {
+            self.x_sum[r] += x[r];
+            self.cos_sum[r] += cos[r];
+            self.sin_sum[r] += sin[r];
+            for c in r..8 {
+                self.xtx[r][c] += x[r] * x[c];
+            }
+            for c in 0..8 {
+                self.xt_cos[r][c] += x[r] * cos[c];
+                self.xt_sin[r][c] += x[r] * sin[c];
+            }
+        }
+        self.samples += 1;
+    }
+
+    pub fn moments(&self) -> Option<Moments> {
+        if self.samples < MIN_SAMPLES {
+            return None;
+        }
+        let n = self.samples as f32;
+        let mean = self.x_sum.map(|x| x / n);
+        let mut cov = [[0.0; 8]; 8];
+        for r in 0..8 {
+            for c in 0..8 {
+                let (i, j) = if r <= c { (r, c) } else { (c, r) };
+                cov[r][c] = self.xtx[i][j] / n - mean[r] * mean[c];
+            }
+        }
+        Some(Moments { mean, cov })
+    }
+
+    pub fn fit(&self) -> Option<Compensation> {
+        let moments = self.moments()?;
+        let n = self.samples as f32;
+        let mean = moments.mean;
+
+        // fit on the signals with their mean removed, the mean is then taken out by the bias
+        let mut xtx = [[0.0; 8]; 8];
+        let mut xt_cos = [[0.0; 8]; 8];
+        let mut xt_sin = [[0.0; 8]; 8];
+        for r in 0..8 {
+            for c in 0..8 {
+                let (i, j) = if r <= c { (r, c) } else { (c, r) };
+                xtx[r][c] = self.xtx[i][j] - n * mean[r] * mean[c];
+                xt_cos[r][c] = self.xt_cos[r][c] - mean[r] * self.cos_sum[c];
+                xt_sin[r][c] = self.xt_sin[r][c] - mean[r] * self.sin_sum[c];
+            }
+        }
+        let trace: f32 = (0..8).map(|i| xtx[i][i]).sum();
+        for i in 0..8 {
+            xtx[i][i] += RIDGE * trace / 8.0;
+        }
+
+        // the fit is linear in the target, so solve for the cos and sin parts separately and then combine them
+        // with the phase each channel actually has
+        let fit_cos = solve(xtx, xt_cos)?;
+        let fit_sin = solve(xtx, xt_sin)?;
+
+        let mut matrix = [[0.0; 8]; 8];
+        for c in 0..8 {
+            let a = xt_cos[c][c];
+            let b = xt_sin[c][c];
+            let amplitude = libm::sqrtf(a * a + b * b);
+            if amplitude == 0.0 {
+                return None;
+            }
+            // normalized signals have unit variance, so aim for a sinusoid of amplitude sqrt(2)
+            let (a, b) = (a / amplitude * core::f32::consts::SQRT_2, b / amplitude * core::f32::consts::SQRT_2);
+            for r in 0..8 {
+                // `Config::comp_matrix[c]` holds the weights for output channel c
+                matrix[c][r] = a * fit_cos[r][c] + b * fit_sin[r][c];
+            }
+        }
+
+        Some(Compensation {
+            matrix,
+            bias: mean.map(|x| -x),
+            moments,
+        })
+    }
+}
+
+// solves a * x = b with gaussian elimination and partial pivoting
+fn solve<const N: usize, const M: usize>(mut a: [[f32; N]; N], mut b: [[f32; M]; N]) -> Option<[[f32; M]; N]> {
+    for col in 0..N {
+        let pivot = (col..N).max_by(|&i, &j| libm::fabsf(a[i][col]).partial_cmp(&libm::fabsf(a[j][col])).unwrap())?;
+        if libm::fabsf(a[pivot][col]) < 1e-12 {
+            return None;
+        }
+        a.swap(col, pivot);
+        b.swap(col, pivot);
+
+        for row in 0..N {
+            if row == col {
+                continue;
+            }
+            let factor = a[row][col] / a[col][col];
+            for k in col..N {
+                a[row][k] -= factor * a[col][k];
+            }
+            for k in 0..M {
+                b[row][k] -= factor * b[col][k];
+            }
+        }
+    }
+
+    for row in 0..N {
+        for k in 0..M {
+            b[row][k] /= a[row][row];
+        }
+    }
+    Some(b)
+}
+
+/// the compensation from the config in the form the encoder applies it, with the bias folded into an offset per
+/// output so each sample is a single multiply-accumulate pass. has to be rebuilt when the config changes
+#[derive(Debug, Clone, PartialEq)]
+pub struct Compensator {
+    matrix: [[f32; 8]; 8], // same layout as `Config::comp_matrix`
+    offset: [f32; 8],
+}
+
+impl Compensator {
+    pub fn new(matrix: &[[f32; 8]; 8], bias: &[f32; 8]) -> Compensator {
+        let mut offset = [0.0; 8];
+        for j in 0..8 {
+            offset[j] = (0..8).map(|i| bias[i] * matrix[j][i]).sum();
+        }
+        Compensator {
+            matrix: *matrix,
+            offset,
+        }
+    }
+
+    pub fn apply(&self, x: &[f32; 8]) -> [f32; 8] {
+        let mut out = self.offset;
+        for j in 0..8 {
+            for i in 0..8 {
+                out[j] += x[i] * self.matrix[j][i];
+            }
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::f32::consts::{PI, TAU};
+
+    const PERIODS: [f32; 8] = [2.34375, 2.34375, 2.34375, 2.34375, 3.0, 3.0, 5.0, 5.0];
+    const PHASES: [f32; 8] = [0.0, PI, PI / 2.0, -PI / 2.0, 0.0, PI / 2.0, 0.0, PI / 2.0];
+
+    // ideal signals with crosstalk between the tracks and offsets added
+    fn signals(position: f32) -> [f32; 8] {
+        let mut ideal = [0.0; 8];
+        for i in 0..8 {
+            ideal[i] = 1.2 * libm::cosf(position / PERIODS[i] * TAU + PHASES[i]);
+        }
+        let mut mixed = ideal;
+        for i in 0..8 {
+            for (j, k) in [((i + 4) % 8, 0.15), ((i + 6) % 8, 0.05)] {
+                if PERIODS[j] != PERIODS[i] {
+                    mixed[i] += k * ideal[j];
+                }
+            }
+            mixed[i] += 0.01 * i as f32;
+        }
+        mixed
+    }
+
+    fn compensate(x: &[f32; 8], comp: &Compensation) -> [f32; 8] {
+        let mut out = [0.0; 8];
+        for j in 0..8 {
+            out[j] = (0..8).map(|i| (x[i] + comp.bias[i]) * comp.matrix[j][i]).sum();
+        }
+        out
+    }
+
+    #[test]
+    fn test_fit() {
+        let mut fitter = CompensationFitter::new();
+        let steps = 20_000;
+        let length = 100.0;
+
+        for i in 0..steps {
+            let position = length - i as f32 / steps as f32 * length;
+            fitter.update_periods(&signals(position), position);
+        }
+        let periods = fitter.periods().unwrap();
+        for i in 0..8 {
+            assert!((periods[i] - PERIODS[i]).abs() < 0.01, "{:?}", periods);
+        }
+
+        for i in 0..steps {
+            let position = i as f32 / steps as f32 * length;
+            fitter.update_fit(&signals(position), position);
+        }
+        let comp = fitter.fit().unwrap();
+
+        // every compensated channel should be a clean sinusoid of its own period
+        for i in 0..steps / 10 {
+            let position = i as f32 / (steps / 10) as f32 * length;
+            let out = compensate(&signals(position), &comp);
+            for c in 0..8 {
+                let expected = core::f32::consts::SQRT_2 * libm::cosf(position / PERIODS[c] * TAU + PHASES[c]);
+                assert!((out[c] - expected).abs() < 0.05, "{} {} {:?}", position, c, out);
+            }
+        }
+    }
+
+    #[test]
+    fn test_compensator() {
+        let matrix: [[f32; 8]; 8] = core::array::from_fn(|j| core::array::from_fn(|i| {
+            if i == j { 1.1 } else { 0.1 * libm::sinf((i * 8 + j) as f32) }
+        }));
+        let bias: [f32; 8] = core::array::from_fn(|i| 0.02 * i as f32 - 0.05);
+        let comp = Compensation {
+            matrix,
+            bias,
+            moments: Moments {
+                mean: [0.0; 8],
+                cov: [[0.0; 8]; 8],
+            },
+        };
+        let compensator = Compensator::new(&matrix, &bias);
+
+        for p in 0..100 {
+            let x = signals(p as f32 * 0.37);
+            let expected = compensate(&x, &comp);
+            let out = compensator.apply(&x);
+            for c in 0..8 {
+                assert!((out[c] - expected[c]).abs() < 1e-5, "{:?} {:?}", out, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn test_solve() {
+        let a = [[2.0, 1.0], [1.0, 3.0]];
+        let b = [[3.0], [5.0]];
+        let x = solve(a, b).unwrap();
+        assert!((x[0][0] - 0.8).abs() < 1e-6);
+        assert!((x[1][0] - 1.4).abs() < 1e-6);
+        assert!(solve([[1.0, 2.0], [2.0, 4.0]], b).is_none());
+    }
+}
@@ -0,0 +1,200 @@
+// the sin and cos of a track trace out an ellipse rather than a circle when their offsets, amplitudes or phases
+// don't match. the ellipse is found from the mean and covariance of the signals over the calibration sweep, which is
+// exact for a sweep covering whole periods at constant speed
+use config::{Config, Ellipse, Signal, SignalSource, Track, MAX_SKEW};
+use crate::compensation::Moments;
+use crate::normalizer::Normalizer;
+
+/// undoes the ellipse, returns the corrected sin and cos. the skew is within `MAX_SKEW`, the fit and
+/// `Config::validate` keep it there
+pub fn correct(ellipse: &Ellipse, sin: f32, cos: f32) -> (f32, f32) {
+    let cos = (cos - ellipse.cos_offset) / ellipse.cos_amplitude;
+    let sin = (sin - ellipse.sin_offset) / ellipse.sin_amplitude;
+    // sin = sin(angle + phi) = sin(angle) cos(phi) + cos(angle) sin(phi), with skew = sin(phi)
+    let sin = (sin - cos * ellipse.skew) / libm::sqrtf(1.0 - ellipse.skew * ellipse.skew);
+    (sin, cos)
+}
+
+fn from_moments(mean_sin: f32, mean_cos: f32, var_sin: f32, var_cos: f32, cov: f32) -> Option<Ellipse> {
+    if !(var_sin > 0.0 && var_cos > 0.0) {
+        return None;
+    }
+    let skew = cov / libm::sqrtf(var_sin * var_cos);
+    if !(-MAX_SKEW..=MAX_SKEW).contains(&skew) {
+        return None;
+    }
+    // a sinusoid of amplitude a has a variance of a^2 / 2
+    Some(Ellipse {
+        sin_offset: mean_sin,
+        cos_offset: mean_cos,
+        sin_amplitude: libm::sqrtf(2.0 * var_sin),
+        cos_amplitude: libm::sqrtf(2.0 * var_cos),
+        skew,
+    })
+}
+
+// weights of the source channels making up a signal
+fn signal_weights(signal: Signal) -> [f32; 8] {
+    let mut weights = [0.0; 8];
+    let mut add = |i: u8, w: f32| {
+        if let Some(x) = weights.get_mut(i as usize) {
+            *x += w;
+        }
+    };
+    match signal {
+        Signal::Single(i) => add(i, 1.0),
+        Signal::Difference([a, b]) => {
+            add(a, 1.0);
+            add(b, -1.0);
+        }
+    }
+    weights
+}
+
+// the source channels as a linear function of the normalized ones, source[j] = sum(a[j][i] * normalized[i]) + b[j]
+fn source_map(source: SignalSource, config: &Config, normalizers: &[Normalizer; 8]) -> ([[f32; 8]; 8], [f32; 8]) {
+    let mut a = [[0.0; 8]; 8];
+    let mut b = [0.0; 8];
+    match source {
+        SignalSource::Raw => {
+            for i in 0..8 {
+                a[i][i] = normalizers[i].std;
+                b[i] = normalizers[i].mean;
+            }
+        }
+        SignalSource::Normalized => {
+            for i in 0..8 {
+                a[i][i] = 1.0;
+            }
+        }
+        SignalSource::Compensated => {
+            a = config.comp_matrix;
+            for j in 0..8 {
+                b[j] = (0..8).map(|i| config.comp_bias[i] * config.comp_matrix[j][i]).sum();
+            }
+        }
+    }
+    (a, b)
+}
+
+/// `config` has to already have the compensation the track will be used with
+pub fn fit(track: &Track, moments: &Moments, normalizers: &[Normalizer; 8], config: &Config) -> Option<Ellipse> {
+    let (a, b) = source_map(track.source, config, normalizers);
+
+    // each signal is v . normalized + offset
+    let linear = |signal: Signal| {
+        let u = signal_weights(signal);
+        let mut v = [0.0; 8];
+        for i in 0..8 {
+            v[i] = (0..8).map(|j| u[j] * a[j][i]).sum();
+        }
+        let offset: f32 = (0..8).map(|j| u[j] * b[j]).sum();
+        (v, offset)
+    };
+    let mean = |(v, offset): ([f32; 8], f32)| (0..8).map(|i| v[i] * moments.mean[i]).sum::<f32>() + offset;
+    let cov = |x: [f32; 8], y: [f32; 8]| {
+        let mut sum = 0.0;
+        for i in 0..8 {
+            for j in 0..8 {
+                sum += x[i] * moments.cov[i][j] * y[j];
+            }
+        }
+        sum
+    };
+
+    let sin = linear(track.sin);
+    let cos = linear(track.cos);
+    from_moments(mean(sin), mean(cos), cov(sin.0, sin.0), cov(cos.0, cos.0), cov(sin.0, cos.0))
+}
+
+/// what the ellipse would be for ideal normalized or compensated signals, which have an amplitude of sqrt(2)
+pub fn nominal(track: &Track) -> Ellipse {
+    let radius = match track.sin {
+        Signal::Single(_) => core::f32::consts::SQRT_2,
+        Signal::Difference(_) => 2.0 * core::f32::consts::SQRT_2,
+    };
+    match track.source {
+        SignalSource::Raw => Ellipse::new(),
+        _ => Ellipse::circle(radius),
+    }
+}
+
+/// refits every track, tracks that can't be fitted get their nominal ellipse
+pub fn fit_tracks(config: &mut Config, moments: &Moments, normalizers: &[Normalizer; 8]) {
+    let ellipses = config.tracks.map(|track| fit(&track, moments, normalizers, config));
+    for (track, ellipse) in config.tracks.iter_mut().zip(ellipses) {
+        track.ellipse = ellipse.unwrap_or(nominal(track));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compensation::CompensationFitter;
+    use core::f32::consts::{PI, TAU};
+
+    const PERIOD: f32 = 2.34375;
+
+    // track 2 of the default layout, with the sin channel 0.2 rad out of quadrature and mismatched in amplitude
+    fn signals(position: f32) -> [f32; 8] {
+        let angle = position / PERIOD * TAU;
+        let mut x = [0.0; 8];
+        x[0] = 1.3 * libm::cosf(angle) + 0.1;
+        x[3] = 0.9 * libm::sinf(angle + 0.2) - 0.2;
+        x[1] = -x[0];
+        x[2] = libm::cosf(angle + PI / 2.0);
+        for i in 4..8 {
+            x[i] = libm::cosf(position / 3.0 * TAU + i as f32);
+        }
+        x
+    }
+
+    #[test]
+    fn test_correct() {
+        let ellipse = from_moments(-0.2, 0.1, 0.9 * 0.9 / 2.0, 1.3 * 1.3 / 2.0, libm::sinf(0.2) * 0.9 * 1.3 / 2.0).unwrap();
+        for i in 0..100 {
+            let angle = i as f32 / 100.0 * TAU - PI;
+            let (sin, cos) = correct(&ellipse, 0.9 * libm::sinf(angle + 0.2) - 0.2, 1.3 * libm::cosf(angle) + 0.1);
+            assert!((sin - libm::sinf(angle)).abs() < 1e-4, "{} {}", angle, sin);
+            assert!((cos - libm::cosf(angle)).abs() < 1e-4, "{} {}", angle, cos);
+        }
+        assert_eq!(correct(&Ellipse::new(), 0.3, -0.4), (0.3, -0.4));
+        // nearly in phase, the correction would blow up the noise
+        assert_eq!(from_moments(0.0, 0.0, 0.5, 0.5, 0.95 * 0.5), None);
+    }
+
+    #[test]
+    fn test_fit() {
+        let mut fitter = CompensationFitter::new();
+        let steps = 20_000;
+        // a whole number of periods
+        let length = 40.0 * PERIOD;
+        for i in 0..steps {
+            let position = length - i as f32 / steps as f32 * length;
+            fitter.update_periods(&signals(position), position);
+        }
+        for i in 0..steps {
+            let position = i as f32 / steps as f32 * length;
+            fitter.update_fit(&signals(position), position);
+        }
+        let moments = fitter.moments().unwrap();
+
+        let mut config = Config::new();
+        config.tracks[1].source = SignalSource::Normalized;
+        let normalizers = [Normalizer { mean: 0.0, std: 1.0 }; 8];
+        let ellipse = fit(&config.tracks[1], &moments, &normalizers, &config).unwrap();
+
+        assert!((ellipse.skew - libm::sinf(0.2)).abs() < 1e-3, "{:?}", ellipse);
+        assert!((ellipse.cos_amplitude - 1.3).abs() < 1e-3, "{:?}", ellipse);
+        assert!((ellipse.sin_offset + 0.2).abs() < 1e-3, "{:?}", ellipse);
+
+        for i in 0..1000 {
+            let position = i as f32 / 1000.0 * PERIOD;
+            let x = signals(position);
+            let (sin, cos) = correct(&ellipse, x[3], x[0]);
+            let error = libm::atan2f(sin, cos) - position / PERIOD * TAU;
+            let error = error - libm::roundf(error / TAU) * TAU;
+            assert!(error.abs() < 2e-3, "{} {}", position, error);
+        }
+    }
+}
@@ -0,0 +1,188 @@
+// checks that the tracks still look like a working encoder. once something goes wrong the unwrapped angles can't be
+// trusted anymore, so the first failure is kept until the encoder is set up again
+use config::{Config, Track};
+use bincode::{Decode, Encode};
+use remote_obj::*;
+use core::f32::consts::TAU;
+
+#[derive(RemoteGetter, RemoteSetter, Debug, Clone, Copy, PartialEq, Encode, Decode)]
+#[remote(derive(Encode, Decode, Debug))]
+pub enum EncoderHealth {
+    Ok,
+    /// sin and cos of the track are too small or too large, from a disconnected or saturated sensor
+    Radius(u8),
+    /// the track no longer agrees with the position track, one of them has slipped a cycle
+    Inconsistent(u8),
+    /// the track moved too close to half a cycle in one sample to be unwrapped reliably
+    Jump(u8),
+}
+
+impl Default for EncoderHealth {
+    fn default() -> Self {
+        EncoderHealth::Ok
+    }
+}
+
+#[derive(RemoteGetter, RemoteSetter, Debug, Clone)]
+#[remote(derive(Encode, Decode, Debug))]
+pub struct HealthMonitor {
+    pub health: EncoderHealth,
+    // difference to the position track at the first sample, in cycles of each track
+    #[remote(skip)]
+    reference: Option<[f32; 4]>,
+    #[remote(skip)]
+    last: [f32; 4],
+    // radius of each track that counts as 1, none to take it from the next sample
+    #[remote(skip)]
+    radius_scale: [Option<f32>; 4],
+}
+
+// in cycles of `track`, how far it is from where the position track says it should be, plus a constant
+fn difference(unwrapped: &[f32; 4], tracks: &[Track; 4], i: usize, reference: usize) -> f32 {
+    (unwrapped[i] - unwrapped[reference] * tracks[reference].period / tracks[i].period) / TAU
+}
+
+impl HealthMonitor {
+    pub fn new() -> HealthMonitor {
+        HealthMonitor {
+            health: EncoderHealth::Ok,
+            reference: None,
+            last: [0.0; 4],
+            radius_scale: [Some(1.0); 4],
+        }
+    }
+
+    /// the next sample becomes the new reference, for when the angles of the tracks are calculated differently from
+    /// now on. a failure that was already found is kept
+    pub fn reset_reference(&mut self) {
+        self.reference = None;
+    }
+
+    /// track `i` switched to another signal source without its ellipse being fitted again, so its radius is off by a
+    /// constant factor. the radius of the next sample is taken as 1
+    pub fn rescale_radius(&mut self, i: usize) {
+        self.radius_scale[i] = None;
+    }
+
+    /// the ellipse of track `i` was fitted for the signals it uses, its radius is 1 again
+    pub fn reset_radius(&mut self, i: usize) {
+        self.radius_scale[i] = Some(1.0);
+    }
+
+    /// `radius` is of the ellipse corrected sin and cos, so 1 for a healthy track. tracks without a period are unused
+    /// and not checked
+    pub fn update(&mut self, radius: &[f32; 4], unwrapped: &[f32; 4], reference: usize, config: &Config) -> EncoderHealth {
+        if self.health == EncoderHealth::Ok {
+            self.health = self.check(radius, unwrapped, reference, config);
+        }
+        self.last = *unwrapped;
+        self.health
+    }
+
+    fn check(&mut self, radius: &[f32; 4], unwrapped: &[f32; 4], reference: usize, config: &Config) -> EncoderHealth {
+        let tracks = &config.tracks;
+        let used = |i: usize| tracks[i].period != 0.0;
+
+        for i in (0..4).filter(|&i| used(i)) {
+            // a sensor that's already dead when the scale is taken divides by 0 and still fails
+            let radius = radius[i] / *self.radius_scale[i].get_or_insert(radius[i]);
+            if !(radius >= config.health_min_radius && radius <= config.health_max_radius) {
+                return EncoderHealth::Radius(i as u8);
+            }
+        }
+
+        let first = self.reference.is_none();
+        if !used(reference) {
+            return EncoderHealth::Ok;
+        }
+        let start = *self.reference.get_or_insert_with(|| {
+            core::array::from_fn(|i| if used(i) { difference(unwrapped, tracks, i, reference) } else { 0.0 })
+        });
+        if first {
+            return EncoderHealth::Ok;
+        }
+
+        for i in (0..4).filter(|&i| used(i)) {
+            if libm::fabsf(unwrapped[i] - self.last[i]) / TAU > config.health_max_step {
+                return EncoderHealth::Jump(i as u8);
+            }
+            if libm::fabsf(difference(unwrapped, tracks, i, reference) - start[i]) > config.health_max_disagreement {
+                return EncoderHealth::Inconsistent(i as u8);
+            }
+        }
+        EncoderHealth::Ok
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> Config {
+        let mut config = Config::new();
+        config.tracks[2].period = 3.0;
+        config
+    }
+
+    // unwrapped angles of a working encoder at `position` mm
+    fn angles(config: &Config, position: f32) -> [f32; 4] {
+        config.tracks.map(|t| if t.period == 0.0 { 0.0 } else { position / t.period * TAU + 0.3 })
+    }
+
+    fn run(config: &Config, monitor: &mut HealthMonitor, positions: impl Iterator<Item = f32>) -> EncoderHealth {
+        for position in positions {
+            monitor.update(&[1.0; 4], &angles(config, position), 1, config);
+        }
+        monitor.health
+    }
+
+    #[test]
+    fn test_healthy() {
+        let config = config();
+        let mut monitor = HealthMonitor::new();
+        assert_eq!(run(&config, &mut monitor, (0..2000).map(|i| i as f32 * 0.05)), EncoderHealth::Ok);
+    }
+
+    #[test]
+    fn test_faults() {
+        let config = config();
+
+        let mut monitor = HealthMonitor::new();
+        run(&config, &mut monitor, (0..10).map(|i| i as f32 * 0.05));
+        assert_eq!(monitor.update(&[1.0, 1.0, 0.1, 1.0], &angles(&config, 0.5), 1, &config), EncoderHealth::Radius(2));
+        // stays failed once the signals come back
+        assert_eq!(run(&config, &mut monitor, (10..20).map(|i| i as f32 * 0.05)), EncoderHealth::Radius(2));
+
+        let mut monitor = HealthMonitor::new();
+        run(&config, &mut monitor, (0..10).map(|i| i as f32 * 0.05));
+        assert_eq!(run(&config, &mut monitor, [1.5].into_iter()), EncoderHealth::Jump(0));
+
+        // track 2 drifts away from the others without any single large step
+        let mut monitor = HealthMonitor::new();
+        run(&config, &mut monitor, (0..10).map(|i| i as f32 * 0.05));
+        let mut drifted = angles(&config, 0.5);
+        drifted[2] += 1.2;
+        assert_eq!(monitor.update(&[1.0; 4], &drifted, 1, &config), EncoderHealth::Inconsistent(2));
+    }
+
+    #[test]
+    fn test_rescale_radius() {
+        let config = config();
+        let mut monitor = HealthMonitor::new();
+        run(&config, &mut monitor, (0..10).map(|i| i as f32 * 0.05));
+
+        // raw adc counts through an ellipse fitted for normalized signals
+        monitor.rescale_radius(2);
+        for i in 10..20 {
+            monitor.update(&[1.0, 1.0, 600.0, 1.0], &angles(&config, i as f32 * 0.05), 1, &config);
+        }
+        assert_eq!(monitor.health, EncoderHealth::Ok);
+        assert_eq!(monitor.update(&[1.0, 1.0, 60.0, 1.0], &angles(&config, 1.0), 1, &config), EncoderHealth::Radius(2));
+
+        // refitted for the raw signals
+        let mut monitor = HealthMonitor::new();
+        monitor.rescale_radius(2);
+        monitor.reset_radius(2);
+        assert_eq!(monitor.update(&[1.0, 1.0, 600.0, 1.0], &angles(&config, 0.0), 1, &config), EncoderHealth::Radius(2));
+    }
+}
@@ -4,12 +4,16 @@
 #[macro_use]
 extern crate std;
 
-use config::Config;
-use nalgebra::{RowSVector, SMatrix};
+use config::{Config, NormalizerMode, Signal, SignalSource, Track};
 use bincode::{Decode, Encode};
 
 pub mod normalizer;
 pub mod unwrap;
+pub mod compensation;
+pub mod vernier;
+pub mod ellipse;
+pub mod linearity;
+pub mod health;
 use biquad::*;
 
 use remote_obj::prelude::*;
@@ -41,22 +45,8 @@ impl EncoderCalibrator {
         normalizers
     }
 
-    pub fn get_encoder(&self) -> Encoder {
-        let f0 = 100.hz();
-        let fs = 8.khz();
-        let coeffs = Coefficients::<f32>::from_params(Type::LowPass, fs, f0, Q_BUTTERWORTH_F32).unwrap();
-        Encoder {
-            normalizers: self.get_normalizers(),
-            unwraps: [unwrap::Unwrapper::new(); 4],
-            normalized: [0.0; 8],
-            compensated: [0.0; 8],
-            unwrapped: [0.0; 4],
-            position: 0.0,
-            filtered_position: 0.0,
-            velocity: 0.0,
-            last_position: None,
-            vel_filter: DirectForm1::<f32>::new(coeffs)
-        }
+    pub fn get_encoder(&self, config: &Config) -> Encoder {
+        Encoder::new(self.get_normalizers(), config)
     }
 }
 
@@ -64,10 +54,22 @@ impl EncoderCalibrator {
 #[remote(derive(Encode, Decode, Debug))]
 pub struct Encoder {
     normalizers: [normalizer::Normalizer; 8],
+    adaptive: [normalizer::AdaptiveNormalizer; 8],
     unwraps: [unwrap::Unwrapper; 4],
     normalized: [f32; 8],
     compensated: [f32; 8],
+    #[remote(skip)]
+    compensator: compensation::Compensator,
+    radius: [f32; 4], // of each track after the ellipse correction
     unwrapped: [f32; 4],
+    health: health::HealthMonitor,
+    // as of the last `update_compensation`, to tell which tracks changed
+    #[remote(skip)]
+    tracks: [Track; 4],
+    absolute: vernier::Absolute,
+    position_offset: f32, // in mm, from the absolute position
+    phase: f32,
+    linearity: f32,
     position: f32,
     filtered_position: f32,
     velocity: f32,
@@ -80,68 +82,163 @@ pub struct Encoder {
 #[derive(RemoteGetter, RemoteSetter, Default, Debug, Clone, PartialEq)]
 #[remote(derive(Encode, Decode, Debug))]
 pub struct EncoderOutput {
-    pub position: f32,
-    pub filtered_position: f32,
-    pub velocity: f32,
+    pub position: f32, // in mm, with the linearity correction applied
+    pub phase: f32, // in cycles of the position track
+    pub linearity: f32, // in mm, correction subtracted from the position
+    pub filtered_position: f32, // in mm
+    pub velocity: f32, // in mm/s
+    pub normalized: [f32; 8],
+    pub health: health::EncoderHealth,
+    pub absolute: vernier::Absolute,
 }
 
-impl EncoderOutput {
-    pub fn multiply(&self, factor: f32) -> EncoderOutput {
-        EncoderOutput {
-            position: self.position * factor,
-            filtered_position: self.filtered_position * factor,
-            velocity: self.velocity * factor,
-        }
+// out of range channels read as 0 rather than panicking in the control loop
+fn signal(s: &[f32; 8], signal: Signal) -> f32 {
+    let get = |i: u8| s.get(i as usize).copied().unwrap_or(0.0);
+    match signal {
+        Signal::Single(i) => get(i),
+        Signal::Difference([a, b]) => get(a) - get(b),
     }
 }
 
+fn velocity_filter(config: &Config) -> Result<Coefficients<f32>, Errors> {
+    let f0 = Hertz::<f32>::from_hz(config.velocity_filter_frequency)?;
+    let fs = Hertz::<f32>::from_hz(1.0 / config.motion_sample_time())?;
+    Coefficients::<f32>::from_params(Type::LowPass, fs, f0, Q_BUTTERWORTH_F32)
+}
+
 impl Encoder {
-    pub fn calculate(&mut self, encoder_values: [f32; 8], config: &Config) -> EncoderOutput {
-        for i in 0..encoder_values.len() {
-            self.normalized[i] = self.normalizers[i].normalize(encoder_values[i])
+    pub fn new(normalizers: [normalizer::Normalizer; 8], config: &Config) -> Encoder {
+        // `Config::validate` rejects a cutoff the filter can't be made for, if one gets here anyway the position goes
+        // through unfiltered instead of panicking
+        let coeffs = velocity_filter(config).unwrap_or(Coefficients { a1: 0.0, a2: 0.0, b0: 1.0, b1: 0.0, b2: 0.0 });
+        Encoder {
+            normalizers,
+            adaptive: normalizers.map(normalizer::AdaptiveNormalizer::new),
+            unwraps: [unwrap::Unwrapper::new(); 4],
+            normalized: [0.0; 8],
+            compensated: [0.0; 8],
+            compensator: compensation::Compensator::new(&config.comp_matrix, &config.comp_bias),
+            radius: [0.0; 4],
+            unwrapped: [0.0; 4],
+            health: health::HealthMonitor::new(),
+            tracks: config.tracks,
+            absolute: vernier::Absolute::Pending,
+            position_offset: 0.0,
+            phase: 0.0,
+            linearity: 0.0,
+            position: 0.0,
+            filtered_position: 0.0,
+            velocity: 0.0,
+            last_position: None,
+            vel_filter: DirectForm1::<f32>::new(coeffs)
         }
+    }
 
-        let input_vec = RowSVector::<f32, 8>::from(self.normalized.clone());
+    pub fn normalizers(&self) -> [normalizer::Normalizer; 8] {
+        self.normalizers
+    }
 
-        let weight_mat = SMatrix::<f32, 8, 8>::from(config.comp_matrix);
+    pub fn health(&self) -> health::EncoderHealth {
+        self.health.health
+    }
 
-        let output = (input_vec + RowSVector::<f32, 8>::from(config.comp_bias)) * weight_mat;
+    pub fn absolute(&self) -> vernier::Absolute {
+        self.absolute
+    }
 
+    /// has to be called after `Config::comp_matrix`, `Config::comp_bias` or the tracks change. `calculate` uses a
+    /// copy of the compensation, and the health monitor has to take a new reference since the angles shift
+    pub fn update_compensation(&mut self, config: &Config) {
+        self.compensator = compensation::Compensator::new(&config.comp_matrix, &config.comp_bias);
+        self.health.reset_reference();
+        // an ellipse is fitted for the source of its track, a new source with the old ellipse changes the radius
+        for (i, (new, old)) in config.tracks.iter().zip(&self.tracks).enumerate() {
+            if new.ellipse != old.ellipse {
+                self.health.reset_radius(i);
+            } else if new.source != old.source {
+                self.health.rescale_radius(i);
+            }
+        }
+        self.tracks = config.tracks;
+    }
+
+    fn signals<'a>(&'a self, raw: &'a [f32; 8], source: SignalSource) -> &'a [f32; 8] {
+        match source {
+            SignalSource::Raw => raw,
+            SignalSource::Normalized => &self.normalized,
+            SignalSource::Compensated => &self.compensated,
+        }
+    }
+
+    pub fn calculate(&mut self, encoder_values: [f32; 8], config: &Config) -> EncoderOutput {
         for i in 0..encoder_values.len() {
-            self.compensated[i] = output[i]
+            self.normalized[i] = self.normalizers[i].normalize(encoder_values[i])
         }
 
-        let angle1 = libm::atan2f(self.normalized[0] - self.normalized[1], self.normalized[2] - self.normalized[3]);
-        let angle2 = libm::atan2f(self.normalized[3], self.normalized[0]);
-        let angle3 = libm::atan2f(self.normalized[4], self.normalized[5]);
-        let angle4 = libm::atan2f(self.normalized[6], self.normalized[7]);
+        self.compensated = self.compensator.apply(&self.normalized);
 
-        let unwrap1 = self.unwraps[0].unwrap(angle1);
-        let unwrap2 = self.unwraps[1].unwrap(angle2);
-        let unwrap3 = self.unwraps[2].unwrap(angle3);
-        let unwrap4 = self.unwraps[3].unwrap(angle4);
+        for (i, track) in config.tracks.iter().enumerate() {
+            let s = self.signals(&encoder_values, track.source);
+            let (sin, cos) = ellipse::correct(&track.ellipse, signal(s, track.sin), signal(s, track.cos));
+            let angle = track.sign * fastmath::atan2(sin, cos);
+            self.radius[i] = libm::sqrtf(sin * sin + cos * cos);
+            self.unwrapped[i] = self.unwraps[i].unwrap(angle);
+        }
 
-        self.unwrapped[0] = unwrap1;
-        self.unwrapped[1] = unwrap2;
-        self.unwrapped[2] = unwrap3;
-        self.unwrapped[3] = unwrap4;
+        let track = config.position_track as usize % config.tracks.len();
+        let period = config.tracks[track].period;
+
+        if self.absolute == vernier::Absolute::Pending {
+            // the unwrappers start from 0, so on the first sample these are still the track angles
+            let phases = self.unwrapped.map(|x| x / core::f32::consts::TAU);
+            let start = (config.position_min + config.position_max - config.vernier_range) / 2.0;
+            match vernier::decode(&phases, &config.tracks, track, start, config.vernier_range, config.vernier_tolerance) {
+                Ok(position) => {
+                    self.position_offset = position - phases[track] * period;
+                    self.absolute = vernier::Absolute::Found;
+                }
+                Err(status) => self.absolute = status,
+            }
+        }
 
-        self.position = unwrap2;
+        let health = self.health.update(&self.radius, &self.unwrapped, track, config);
+
+        self.phase = linearity::phase(self.unwrapped[track]);
+        self.linearity = linearity::correction(&config.linearity_lut, self.phase);
+        self.position = self.unwrapped[track] * period / core::f32::consts::TAU + self.position_offset - self.linearity;
 
         self.filtered_position = self.vel_filter.run(self.position);
 
         if let Some(last_pos) = self.last_position {
-            self.velocity = (self.filtered_position - last_pos) * 8e3;
+            self.velocity = (self.filtered_position - last_pos) / config.motion_sample_time();
         } else {
             self.velocity = 0.0;
         }
 
         self.last_position = Some(self.filtered_position);
 
+        // stationary signals only cover part of a period, which would pull the normalizers off
+        let speed = libm::fabsf(self.velocity);
+        if config.normalizer_mode == NormalizerMode::Adaptive && health == health::EncoderHealth::Ok &&
+            speed >= config.adapt_min_velocity {
+            let dt = config.motion_sample_time();
+            let weight = (speed * dt / config.adapt_length).min(1.0);
+            for i in 0..8 {
+                self.adaptive[i].update(encoder_values[i], weight, config.adapt_max_rate * dt);
+                self.normalizers[i] = self.adaptive[i].get_normalizer();
+            }
+        }
+
         EncoderOutput{
             position: self.position,
+            phase: self.phase,
+            linearity: self.linearity,
             filtered_position: self.filtered_position,
-            velocity: self.velocity
+            velocity: self.velocity,
+            normalized: self.normalized,
+            health,
+            absolute: self.absolute,
         }
     }
 }
@@ -170,10 +267,36 @@ impl EncoderState {
         }
     }
 
-    pub fn calibration_done(&mut self) {
+    pub fn calibration_done(&mut self, config: &Config) {
         match self {
             EncoderState::Calibrating(calibrator) => {
-                *self = EncoderState::Running(calibrator.get_encoder());
+                *self = EncoderState::Running(calibrator.get_encoder(config));
+            }
+            _ => {}
+        }
+    }
+
+    pub fn import(&mut self, normalizers: [normalizer::Normalizer; 8], config: &Config) {
+        *self = EncoderState::Running(Encoder::new(normalizers, config));
+    }
+
+    pub fn update_compensation(&mut self, config: &Config) {
+        if let EncoderState::Running(encoder) = self {
+            encoder.update_compensation(config);
+        }
+    }
+
+    pub fn normalizers(&self) -> Option<[normalizer::Normalizer; 8]> {
+        match self {
+            EncoderState::Running(encoder) => Some(encoder.normalizers()),
+            _ => None,
+        }
+    }
+
+    pub fn restart_calibration(&mut self) {
+        match self {
+            EncoderState::Running(_) => {
+                *self = EncoderState::new();
             }
             _ => {}
         }
@@ -183,10 +306,14 @@ impl EncoderState {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use core::f32::consts::{PI, TAU};
+    use std::vec::Vec;
+    use nalgebra::{RowSVector, SMatrix};
 
     #[test]
     fn test_linalg() {
-        let input_vec = RowSVector::<f32, 8>::from([-0.2862,  0.3974,  0.6592, -0.9261,  0.6866, -0.2952, -0.3340, -0.7781]);
+        let input = [-0.2862,  0.3974,  0.6592, -0.9261,  0.6866, -0.2952, -0.3340, -0.7781];
+        let input_vec = RowSVector::<f32, 8>::from(input);
 
         let weight_mat = SMatrix::<f32, 8, 8>::from([[ 1.1173, -0.8311,  0.2963, -0.3230,  0.0120,  0.0302,  0.0000,  0.0000],
             [-1.0591,  0.9015, -0.3551,  0.2872, -0.0080, -0.0282,  0.0000,  0.0000],
@@ -197,8 +324,280 @@ mod tests {
             [ 0.0000,  0.0000,  0.0000,  0.0000,  0.0084, -0.0191,  1.3382, -0.2344],
             [ 0.0000,  0.0000,  0.0000,  0.0000, -0.0405,  0.1293, -0.2132,  1.4222]]);
 
-        let output = (input_vec + RowSVector::<f32, 8>::from([-0.0047, -0.0263, -0.0321, -0.0069, -0.0205, -0.0365, -0.0312, -0.0245])) * weight_mat;
+        let bias = [-0.0047, -0.0263, -0.0321, -0.0069, -0.0205, -0.0365, -0.0312, -0.0245];
+        let output = (input_vec + RowSVector::<f32, 8>::from(bias)) * weight_mat;
+        println!("{:?}", output);
+
+        // the compensator has to agree with the matrix layout nalgebra gives the config
+        let matrix: [[f32; 8]; 8] = core::array::from_fn(|j| core::array::from_fn(|i| weight_mat[(i, j)]));
+        let compensated = compensation::Compensator::new(&matrix, &bias).apply(&input);
+        for i in 0..8 {
+            assert!((compensated[i] - output[i]).abs() < 1e-5, "{:?}", compensated);
+        }
+    }
+
+    const PERIODS: [f32; 8] = [2.34375, 2.34375, 2.34375, 2.34375, 3.0, 3.0, 5.0, 5.0];
+    const PHASES: [f32; 8] = [0.0, PI, PI / 2.0, -PI / 2.0, 0.0, PI / 2.0, 0.0, PI / 2.0];
+
+    // adc counts for ideal signals with crosstalk between the tracks
+    fn signals(position: f32) -> [f32; 8] {
+        let mut ideal = [0.0; 8];
+        for i in 0..8 {
+            ideal[i] = libm::cosf(position / PERIODS[i] * TAU + PHASES[i]);
+        }
+        let mut raw = [0.0; 8];
+        for i in 0..8 {
+            let mut x = ideal[i];
+            for (j, k) in [((i + 4) % 8, 0.15), ((i + 6) % 8, 0.05)] {
+                if PERIODS[j] != PERIODS[i] {
+                    x += k * ideal[j];
+                }
+            }
+            raw[i] = 2000.0 + 50.0 * i as f32 + (600.0 + 20.0 * i as f32) * x;
+        }
+        raw
+    }
+
+    fn set_source(config: &mut Config, source: SignalSource) {
+        for track in config.tracks.iter_mut() {
+            track.source = source;
+        }
+    }
+
+    // largest deviation of the position from the true one, after removing the constant offset
+    fn position_error(encoder: &mut Encoder, config: &Config, positions: &[f32]) -> f32 {
+        let errors: Vec<f32> = positions.iter()
+            .map(|&p| encoder.calculate(signals(p), config).position - p)
+            .collect();
+        let mean = errors.iter().sum::<f32>() / errors.len() as f32;
+        errors.iter().map(|e| (e - mean).abs()).fold(0.0, f32::max)
+    }
+
+    #[test]
+    fn test_compensated_position() {
+        let length = 100.0;
+        let positions: Vec<f32> = (0..20_000).map(|i| i as f32 / 20_000.0 * length).collect();
+
+        let mut calibrator = EncoderCalibrator::new();
+        for &p in &positions {
+            calibrator.update(signals(p));
+        }
+        let mut encoder = calibrator.get_encoder(&Config::new());
+
+        // the same two passes as the calibration sweep
+        let normalize = |p: f32| -> [f32; 8] {
+            let x = signals(p);
+            core::array::from_fn(|i| encoder.normalizers[i].normalize(x[i]))
+        };
+        let mut fitter = compensation::CompensationFitter::new();
+        for &p in positions.iter().rev() {
+            fitter.update_periods(&normalize(p), p);
+        }
+        for &p in &positions {
+            fitter.update_fit(&normalize(p), p);
+        }
+        let comp = fitter.fit().unwrap();
+
+        let mut config = Config::new();
+        config.comp_matrix = comp.matrix;
+        config.comp_bias = comp.bias;
+        encoder.update_compensation(&config);
+
+        set_source(&mut config, SignalSource::Normalized);
+        let normalized_error = position_error(&mut encoder.clone(), &config, &positions);
+        set_source(&mut config, SignalSource::Compensated);
+        let compensated_error = position_error(&mut encoder.clone(), &config, &positions);
+
+        println!("max error normalized {} compensated {} mm", normalized_error, compensated_error);
+        assert!(normalized_error > 0.04);
+        assert!(compensated_error < 0.01);
+    }
+
+    #[test]
+    fn test_compensation_swap() {
+        let positions: Vec<f32> = (0..10_000).map(|i| i as f32 / 10_000.0 * 40.0).collect();
+        let mut calibrator = EncoderCalibrator::new();
+        for &p in &positions {
+            calibrator.update(signals(p));
+        }
+        let mut config = Config::new();
+        set_source(&mut config, SignalSource::Compensated);
+        config.tracks[2].period = PERIODS[4];
+        config.comp_matrix = core::array::from_fn(|j| core::array::from_fn(|i| if i == j { 1.0 } else { 0.0 }));
+        config.comp_bias = [0.0; 8];
+        let mut encoder = calibrator.get_encoder(&config);
+
+        for &p in &positions[..5_000] {
+            encoder.calculate(signals(p), &config);
+        }
+        assert_eq!(encoder.health(), health::EncoderHealth::Ok);
+
+        // a new compensation mid-move that turns track 2 by more than the tracks may disagree, like a fit at the end
+        // of the calibration sweep can
+        let turn = 0.2 * TAU;
+        let (sin, cos) = (libm::sinf(turn), libm::cosf(turn));
+        config.comp_matrix[4][4] = cos;
+        config.comp_matrix[4][5] = -sin;
+        config.comp_matrix[5][4] = sin;
+        config.comp_matrix[5][5] = cos;
+        encoder.update_compensation(&config);
+        for &p in &positions[5_000..] {
+            encoder.calculate(signals(p), &config);
+        }
+        assert_eq!(encoder.health(), health::EncoderHealth::Ok);
+    }
+
+    #[test]
+    fn test_invalid_velocity_filter() {
+        let positions: Vec<f32> = (0..5_000).map(|i| i as f32 / 5_000.0 * 20.0).collect();
+        let mut calibrator = EncoderCalibrator::new();
+        for &p in &positions {
+            calibrator.update(signals(p));
+        }
+        let mut config = Config::new();
+        config.velocity_filter_frequency = 0.0;
+        assert!(config.validate().is_err());
+
+        let mut encoder = calibrator.get_encoder(&config);
+        for &p in &positions {
+            let output = encoder.calculate(signals(p), &config);
+            assert_eq!(output.filtered_position, output.position);
+        }
+    }
+
+    #[test]
+    fn test_track_layout() {
+        // a board with the channels connected in the opposite order and the sin coil wound the other way
+        let reversed = |p: f32| {
+            let mut x = signals(p);
+            x.reverse();
+            x[4] = 4000.0 - x[4];
+            x
+        };
+        let positions: Vec<f32> = (0..5_000).map(|i| i as f32 / 5_000.0 * 20.0).collect();
+
+        let mut config = Config::new();
+        set_source(&mut config, SignalSource::Normalized);
+        let mut reversed_config = config.clone();
+        reversed_config.tracks[1].sin = Signal::Single(4);
+        reversed_config.tracks[1].cos = Signal::Single(7);
+        reversed_config.tracks[1].sign = -1.0;
+
+        let mut calibrator = EncoderCalibrator::new();
+        let mut reversed_calibrator = EncoderCalibrator::new();
+        for &p in &positions {
+            calibrator.update(signals(p));
+            reversed_calibrator.update(reversed(p));
+        }
+        let mut encoder = calibrator.get_encoder(&config);
+        let mut reversed_encoder = reversed_calibrator.get_encoder(&reversed_config);
+
+        for &p in &positions {
+            let expected = encoder.calculate(signals(p), &config).position;
+            let position = reversed_encoder.calculate(reversed(p), &reversed_config).position;
+            assert!((position - expected).abs() < 1e-3, "{} {} {}", p, position, expected);
+        }
+    }
+
+    #[test]
+    fn test_source_switch() {
+        // without the adc offsets the raw signals only differ from the normalized ones in scale
+        let centered = |p: f32| -> [f32; 8] {
+            let x = signals(p);
+            core::array::from_fn(|i| x[i] - (2000.0 + 50.0 * i as f32))
+        };
+        let positions: Vec<f32> = (0..10_000).map(|i| i as f32 / 10_000.0 * 40.0).collect();
+        let mut calibrator = EncoderCalibrator::new();
+        for &p in &positions {
+            calibrator.update(centered(p));
+        }
+        let mut config = Config::new();
+        let mut encoder = calibrator.get_encoder(&config);
+        for &p in &positions[..5_000] {
+            encoder.calculate(centered(p), &config);
+        }
+
+        // the ellipses are still the ones for normalized signals
+        set_source(&mut config, SignalSource::Raw);
+        encoder.update_compensation(&config);
+        for &p in &positions[5_000..] {
+            encoder.calculate(centered(p), &config);
+        }
+        assert_eq!(encoder.health(), health::EncoderHealth::Ok);
+    }
+
+    #[test]
+    fn test_absolute_position() {
+        let mut config = Config::new();
+        set_source(&mut config, SignalSource::Normalized);
+        let offsets = [0.25, 0.0, 0.25, 0.25];
+        for i in 0..4 {
+            config.tracks[i].period = PERIODS[2 * i];
+            config.tracks[i].offset = offsets[i];
+        }
+        config.vernier_range = 75.0;
+
+        let mut calibrator = EncoderCalibrator::new();
+        for i in 0..20_000 {
+            calibrator.update(signals(-100.0 + i as f32 * 0.006));
+        }
+        let encoder = calibrator.get_encoder(&config);
+
+        for i in 0..60 {
+            let start = -70.0 + i as f32;
+            let mut encoder = encoder.clone();
+            let position = encoder.calculate(signals(start), &config).position;
+            assert_eq!(encoder.absolute(), vernier::Absolute::Found);
+            assert!((position - start).abs() < 0.15, "{} {}", start, position);
+
+            let position = encoder.calculate(signals(start + 0.5), &config).position;
+            assert!((position - start - 0.5).abs() < 0.15, "{} {}", start, position);
+        }
+    }
+
+    // adc channels 0 to 8 while moving back and forth, in the layout of `GUI::save_data`. `ENCODER_RECORDING` can point
+    // at one taken on the hardware, otherwise the small generated one in tests/data is used
+    fn load_recording() -> Vec<[f32; 8]> {
+        let path = std::env::var("ENCODER_RECORDING")
+            .unwrap_or_else(|_| concat!(env!("CARGO_MANIFEST_DIR"), "/tests/data/recording.npy").into());
+        let bytes = std::fs::read(path).unwrap();
+        let npy = npyz::NpyFile::new(&bytes[..]).unwrap();
+        assert_eq!(npy.shape()[0], 9);
+        let samples = npy.shape()[1] as usize;
+        let data: Vec<f32> = npy.into_vec().unwrap();
+        (0..samples).map(|i| core::array::from_fn(|c| data[(c + 1) * samples + i])).collect()
+    }
+
+    // tracks 1 and 2 are computed from the same channels in different ways, so without a reference position the
+    // spread of their difference is used as the measure of accuracy
+    fn track_disagreement(encoder: &mut Encoder, config: &Config, recording: &[[f32; 8]]) -> f32 {
+        let differences: Vec<f32> = recording.iter()
+            .map(|&x| {
+                encoder.calculate(x, config);
+                encoder.unwrapped[0] - encoder.unwrapped[1]
+            })
+            .collect();
+        let mean = differences.iter().sum::<f32>() / differences.len() as f32;
+        libm::sqrtf(differences.iter().map(|d| (d - mean) * (d - mean)).sum::<f32>() / differences.len() as f32)
+    }
+
+    #[test]
+    fn test_recorded_signal_source() {
+        let recording = load_recording();
+
+        let mut calibrator = EncoderCalibrator::new();
+        for &x in &recording {
+            calibrator.update(x);
+        }
+        let encoder = calibrator.get_encoder(&Config::new());
+
+        let mut config = Config::new();
+        set_source(&mut config, SignalSource::Normalized);
+        let normalized = track_disagreement(&mut encoder.clone(), &config, &recording);
+        set_source(&mut config, SignalSource::Compensated);
+        let compensated = track_disagreement(&mut encoder.clone(), &config, &recording);
 
-        println!("{:?}", output)
+        println!("track disagreement normalized {} compensated {} rad", normalized, compensated);
+        assert!(compensated < normalized);
     }
 }
\ No newline at end of file
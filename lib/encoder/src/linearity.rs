@@ -0,0 +1,99 @@
+// position error against the open loop reference, binned by where in the period of the position track it happens.
+// the reference lags behind in the direction of motion, so sweeping both ways and averaging cancels the lag
+use config::LINEARITY_BINS;
+
+/// in cycles of the position track, in [0, 1)
+pub fn phase(angle: f32) -> f32 {
+    let cycles = angle / core::f32::consts::TAU;
+    cycles - libm::floorf(cycles)
+}
+
+/// correction to subtract from the position, linearly interpolated between the bin centers
+pub fn correction(lut: &[f32; LINEARITY_BINS], phase: f32) -> f32 {
+    let x = phase * LINEARITY_BINS as f32 - 0.5;
+    let i = libm::floorf(x);
+    let t = x - i;
+    let a = (i as i32).rem_euclid(LINEARITY_BINS as i32) as usize;
+    let b = (a + 1) % LINEARITY_BINS;
+    lut[a] * (1.0 - t) + lut[b] * t
+}
+
+#[derive(Debug, Clone)]
+pub struct LinearityBuilder {
+    sum: [[f32; LINEARITY_BINS]; 2],
+    count: [[u32; LINEARITY_BINS]; 2],
+}
+
+impl LinearityBuilder {
+    pub fn new() -> LinearityBuilder {
+        LinearityBuilder {
+            sum: [[0.0; LINEARITY_BINS]; 2],
+            count: [[0; LINEARITY_BINS]; 2],
+        }
+    }
+
+    /// `error` is in mm, with any correction already applied added back on
+    pub fn update(&mut self, forward: bool, phase: f32, error: f32) {
+        let bin = ((phase * LINEARITY_BINS as f32) as usize).min(LINEARITY_BINS - 1);
+        let direction = forward as usize;
+        self.sum[direction][bin] += error;
+        self.count[direction][bin] += 1;
+    }
+
+    /// mean error and the lookup table with it removed, once every bin has been seen in both directions
+    pub fn result(&self) -> Option<(f32, [f32; LINEARITY_BINS])> {
+        let mut lut = [0.0; LINEARITY_BINS];
+        for i in 0..LINEARITY_BINS {
+            if self.count[0][i] == 0 || self.count[1][i] == 0 {
+                return None;
+            }
+            let mean = |d: usize| self.sum[d][i] / self.count[d][i] as f32;
+            lut[i] = (mean(0) + mean(1)) / 2.0;
+        }
+        let mean = lut.iter().sum::<f32>() / LINEARITY_BINS as f32;
+        Some((mean, lut.map(|x| x - mean)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::f32::consts::TAU;
+
+    fn error(phase: f32) -> f32 {
+        0.05 * libm::sinf(phase * TAU) + 0.02 * libm::cosf(2.0 * phase * TAU)
+    }
+
+    #[test]
+    fn test_lut() {
+        let mut builder = LinearityBuilder::new();
+        assert!(builder.result().is_none());
+
+        // lag of 0.3 mm behind the direction of motion, on top of an offset of 1.5 mm
+        for i in 0..10_000 {
+            let phase = (i as f32 * 0.0137) % 1.0;
+            builder.update(false, phase, error(phase) + 1.5 - 0.3);
+            builder.update(true, phase, error(phase) + 1.5 + 0.3);
+        }
+        let (mean, lut) = builder.result().unwrap();
+        assert!((mean - 1.5).abs() < 1e-3, "{}", mean);
+
+        for i in 0..1000 {
+            let phase = i as f32 / 1000.0;
+            // the bins average over their width, which flattens the peaks a little
+            assert!((correction(&lut, phase) - error(phase)).abs() < 3e-3, "{} {}", phase, correction(&lut, phase));
+        }
+    }
+
+    #[test]
+    fn test_correction() {
+        let mut lut = [0.0; LINEARITY_BINS];
+        lut[0] = 1.0;
+        assert_eq!(correction(&lut, 0.5 / LINEARITY_BINS as f32), 1.0);
+        assert_eq!(correction(&lut, 1.5 / LINEARITY_BINS as f32), 0.0);
+        // wraps around between the last and first bins
+        assert!((correction(&lut, 0.0) - 0.5).abs() < 1e-6);
+        assert!((correction(&lut, 1.0 - 0.25 / LINEARITY_BINS as f32) - 0.25).abs() < 1e-6);
+        assert!((phase(-0.25 * TAU) - 0.75).abs() < 1e-6);
+    }
+}
@@ -8,6 +8,8 @@ pub struct NormalizerBuilder {
     k: f32,
     ex2: f32,
     ex: f32,
+    min: f32,
+    max: f32,
 }
 
 impl NormalizerBuilder {
@@ -17,6 +19,8 @@ impl NormalizerBuilder {
             k: 0.0,
             ex: 0.0,
             ex2: 0.0,
+            min: f32::INFINITY,
+            max: f32::NEG_INFINITY,
         }
     }
 
@@ -24,11 +28,22 @@ impl NormalizerBuilder {
         if self.n == 0 {
             self.k = x;
         }
+        self.min = self.min.min(x);
+        self.max = self.max.max(x);
         self.n += 1;
         self.ex += x - self.k;
         self.ex2 += (x - self.k) * (x - self.k);
     }
 
+    pub fn count(&self) -> u32 {
+        self.n
+    }
+
+    // smallest and largest values seen
+    pub fn range(&self) -> (f32, f32) {
+        (self.min, self.max)
+    }
+
     pub fn get_normalizer(&self) -> Option<Normalizer> {
         if self.n > 2 {
             let var = (self.ex2 - self.ex * self.ex / self.n as f32) / (self.n as f32 - 1.0);
@@ -45,7 +60,7 @@ impl NormalizerBuilder {
     }
 }
 
-#[derive(RemoteGetter, RemoteSetter, Debug, Clone, Copy, PartialEq, Default)]
+#[derive(RemoteGetter, RemoteSetter, Debug, Clone, Copy, PartialEq, Default, Encode, Decode)]
 #[remote(derive(Encode, Decode, Debug))]
 pub struct Normalizer{
     pub mean: f32,
@@ -65,6 +80,47 @@ impl Normalizer {
     }
 }
 
+/// follows slow drift of a channel's offset and gain after calibration. the estimate is exponentially weighted over
+/// distance travelled rather than time, so it always spans the same number of periods, and the normalizer in use
+/// slews towards it at a bounded rate
+#[derive(Debug, Clone, Copy, RemoteGetter, RemoteSetter)]
+#[remote(derive(Encode, Decode, Debug))]
+pub struct AdaptiveNormalizer {
+    mean_estimate: f32,
+    var_estimate: f32,
+    mean: f32,
+    std: f32,
+}
+
+impl AdaptiveNormalizer {
+    pub fn new(normalizer: Normalizer) -> AdaptiveNormalizer {
+        AdaptiveNormalizer {
+            mean_estimate: normalizer.mean,
+            var_estimate: normalizer.std * normalizer.std,
+            mean: normalizer.mean,
+            std: normalizer.std,
+        }
+    }
+
+    /// `weight` is how much of the estimate this sample replaces, `max_step` is the largest change of the mean or std
+    /// in use, as a fraction of the std
+    pub fn update(&mut self, x: f32, weight: f32, max_step: f32) {
+        self.mean_estimate += weight * (x - self.mean_estimate);
+        let e = x - self.mean_estimate;
+        self.var_estimate += weight * (e * e - self.var_estimate);
+
+        let limit = max_step * self.std;
+        self.mean += (self.mean_estimate - self.mean).clamp(-limit, limit);
+        self.std += (libm::sqrtf(self.var_estimate) - self.std).clamp(-limit, limit);
+    }
+
+    pub fn get_normalizer(&self) -> Normalizer {
+        Normalizer {
+            mean: self.mean,
+            std: self.std,
+        }
+    }
+}
 
 #[cfg(test)]
 mod tests {
@@ -84,8 +140,10 @@ mod tests {
         }
 
         let n = b.get_normalizer().unwrap();
+        let (min, max) = b.range();
 
         assert!((n.mean - 5.0).abs() < 0.1);
+        assert!(min < n.mean - 3.0 && max > n.mean + 3.0);
         assert!((n.std - 3.0).abs() < 0.1);
 
         let mut b2 = NormalizerBuilder::new();
@@ -99,4 +157,33 @@ mod tests {
         assert!(n.mean.abs() < 0.1);
         assert!((n.std - 1.0).abs() < 0.1);
     }
+
+    #[test]
+    fn test_adaptive() {
+        // 50 mm/s at 8 kHz on a 2.34375 mm track, averaging over 100 mm
+        let step = 50.0 / 8e3;
+        let weight = step / 100.0;
+        let max_step = 0.05 / 8e3;
+
+        let mut n = AdaptiveNormalizer::new(Normalizer { mean: 2000.0, std: 1000.0 / core::f32::consts::SQRT_2 });
+        let samples = 480_000;
+        for i in 0..samples {
+            let t = i as f32 / samples as f32;
+            // gain and offset drift over a minute
+            let gain = 1000.0 + 100.0 * t;
+            let offset = 2000.0 + 30.0 * t;
+            let angle = i as f32 * step / 2.34375 * core::f32::consts::TAU;
+            n.update(offset + gain * libm::cosf(angle), weight, max_step);
+        }
+        let normalizer = n.get_normalizer();
+        assert!((normalizer.mean - 2030.0).abs() < 10.0, "{:?}", normalizer);
+        assert!((normalizer.std - 1100.0 / core::f32::consts::SQRT_2).abs() < 10.0, "{:?}", normalizer);
+
+        // a glitch can only move it by the rate limit
+        let before = n.get_normalizer();
+        n.update(4095.0, 0.5, 1e-3);
+        let after = n.get_normalizer();
+        assert!((after.mean - before.mean).abs() <= 1e-3 * before.std * 1.0001);
+        assert!((after.std - before.std).abs() <= 1e-3 * before.std * 1.0001);
+    }
 }
\ No newline at end of file
@@ -0,0 +1,142 @@
+// absolute position from tracks of different periods. the reference track gives the position within one of its
+// periods, and the other tracks pick which period it is in
+use config::Track;
+use bincode::{Decode, Encode};
+use remote_obj::*;
+
+#[derive(RemoteGetter, RemoteSetter, Debug, Clone, Copy, PartialEq, Encode, Decode)]
+#[remote(derive(Encode, Decode, Debug))]
+pub enum Absolute {
+    /// waiting for the first sample
+    Pending,
+    /// no range configured, fewer than two tracks with different periods, or a range or period that can't be used
+    Disabled,
+    /// position is absolute
+    Found,
+    /// no position within the range agrees with all tracks, position is relative to power up
+    Inconsistent,
+    /// more than one position agrees with all tracks, the range is longer than the combined period
+    Ambiguous,
+}
+
+impl Default for Absolute {
+    fn default() -> Self {
+        Absolute::Pending
+    }
+}
+
+// periods of the reference track within the range to check, a longer range can't be told apart anyway
+const MAX_CANDIDATES: u32 = 1024;
+
+// distance to the nearest whole number, in [-0.5, 0.5)
+fn wrap(cycles: f32) -> f32 {
+    cycles - libm::floorf(cycles + 0.5)
+}
+
+/// `phases` are the track angles in cycles, before removing `Track::offset`. returns the only position in
+/// `[start, start + range)` that every track with a known period agrees with to within `tolerance` cycles
+pub fn decode(phases: &[f32; 4], tracks: &[Track; 4], reference: usize, start: f32, range: f32, tolerance: f32) -> Result<f32, Absolute> {
+    let period = tracks[reference].period;
+    let finite = start.is_finite() && range.is_finite() && tolerance.is_finite() &&
+        tracks.iter().all(|t| t.period.is_finite());
+    if !finite || range <= 0.0 || period <= 0.0 || !tracks.iter().any(|t| t.period != 0.0 && t.period != period) {
+        return Err(Absolute::Disabled);
+    }
+    // tracks with the same period as the reference don't help pick the period, but are still checked
+    let known = || tracks.iter().enumerate().filter(|(_, t)| t.period != 0.0);
+
+    let phase = |i: usize| phases[i] - tracks[i].offset;
+    if !phase(reference).is_finite() {
+        return Err(Absolute::Inconsistent);
+    }
+
+    // first candidate at or after `start`, and how many there are before `start + range`
+    let first = libm::ceilf(start / period - phase(reference));
+    let count = libm::ceilf((start + range) / period - phase(reference)) - first;
+    if !(count <= MAX_CANDIDATES as f32) {
+        return Err(Absolute::Disabled);
+    }
+    let mut found = None;
+    for k in 0..count.max(0.0) as u32 {
+        let position = (phase(reference) + first + k as f32) * period;
+        let consistent = known().all(|(i, t)| libm::fabsf(wrap(phase(i) - position / t.period)) < tolerance);
+        if consistent {
+            if found.is_some() {
+                return Err(Absolute::Ambiguous);
+            }
+            found = Some(position);
+        }
+    }
+    found.ok_or(Absolute::Inconsistent)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::{Config, SignalSource};
+
+    fn tracks() -> [Track; 4] {
+        let mut tracks = Config::new().tracks;
+        let periods = [2.34375, 2.34375, 3.0, 5.0];
+        let offsets = [0.25, 0.1, -0.3, 0.45];
+        for i in 0..4 {
+            tracks[i].period = periods[i];
+            tracks[i].offset = offsets[i];
+            tracks[i].source = SignalSource::Compensated;
+        }
+        tracks
+    }
+
+    // angles as the encoder would see them, in (-0.5, 0.5] cycles
+    fn phases(position: f32, tracks: &[Track; 4], error: [f32; 4]) -> [f32; 4] {
+        let mut phases = [0.0; 4];
+        for i in 0..4 {
+            phases[i] = wrap(position / tracks[i].period + tracks[i].offset + error[i]);
+        }
+        phases
+    }
+
+    #[test]
+    fn test_decode() {
+        let tracks = tracks();
+        // combined period of 2.34375, 3 and 5 mm is 75 mm
+        for i in 0..7000 {
+            let position = -35.0 + i as f32 * 0.01;
+            let error = [0.02, -0.02, 0.03, -0.03];
+            let decoded = decode(&phases(position, &tracks, error), &tracks, 1, -37.5, 75.0, 0.08).unwrap();
+            // only the error of the reference track shows up in the result
+            assert!((decoded - position + 0.02 * 2.34375).abs() < 1e-3, "{} {}", position, decoded);
+        }
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        let tracks = tracks();
+        let clean = phases(12.3, &tracks, [0.0; 4]);
+        assert_eq!(decode(&clean, &tracks, 1, -37.5, 0.0, 0.08), Err(Absolute::Disabled));
+        assert_eq!(decode(&clean, &tracks, 1, -37.5, 150.0, 0.08), Err(Absolute::Ambiguous));
+
+        let bad = phases(12.3, &tracks, [0.0, 0.0, 0.15, 0.0]);
+        assert_eq!(decode(&bad, &tracks, 1, -37.5, 75.0, 0.08), Err(Absolute::Inconsistent));
+        let bad = phases(12.3, &tracks, [0.25, 0.0, 0.0, 0.0]);
+        assert_eq!(decode(&bad, &tracks, 1, -37.5, 75.0, 0.08), Err(Absolute::Inconsistent));
+
+        // anything that would leave the search without a bound
+        assert_eq!(decode(&clean, &tracks, 1, -37.5, f32::NAN, 0.08), Err(Absolute::Disabled));
+        assert_eq!(decode(&clean, &tracks, 1, -37.5, f32::INFINITY, 0.08), Err(Absolute::Disabled));
+        assert_eq!(decode(&clean, &tracks, 1, f32::NEG_INFINITY, 75.0, 0.08), Err(Absolute::Disabled));
+        assert_eq!(decode(&clean, &tracks, 1, -37.5, 75.0, f32::NAN), Err(Absolute::Disabled));
+        assert_eq!(decode(&clean, &tracks, 1, -37.5, 1e9, 0.08), Err(Absolute::Disabled));
+        let mut negative = tracks;
+        negative[1].period = -2.34375;
+        assert_eq!(decode(&clean, &negative, 1, -37.5, 75.0, 0.08), Err(Absolute::Disabled));
+        let mut nan = clean;
+        nan[1] = f32::NAN;
+        assert_eq!(decode(&nan, &tracks, 1, -37.5, 75.0, 0.08), Err(Absolute::Inconsistent));
+
+        let mut single = tracks;
+        single[2].period = 0.0;
+        single[3].period = 0.0;
+        assert_eq!(decode(&clean, &single, 1, -37.5, 75.0, 0.08), Err(Absolute::Disabled));
+    }
+}
@@ -0,0 +1,75 @@
+# writes recording.npy, adc samples in the layout of `GUI::save_data` for `test_recorded_signal_source`. the signals are
+# generated rather than captured: ideal sinusoids mixed by the inverse of the default `comp_matrix` and `comp_bias`, so
+# the default compensation removes the crosstalk, then scaled to adc counts with noise and quantization
+import math
+import random
+import struct
+
+SAMPLES = 4000
+PERIODS = [2.34375, 2.34375, 2.34375, 2.34375, 3.0, 3.0, 5.0, 5.0]
+PHASES = [0.0, math.pi, math.pi / 2, -math.pi / 2, 0.0, math.pi / 2, 0.0, math.pi / 2]
+# from `Config::new`
+MATRIX = [
+    [ 1.1173, -0.8311,  0.2963, -0.3230,  0.0120,  0.0302,  0.0000,  0.0000],
+    [-1.0591,  0.9015, -0.3551,  0.2872, -0.0080, -0.0282,  0.0000,  0.0000],
+    [ 0.3346, -0.3011,  0.7365, -0.7179,  0.0443,  0.0641,  0.0000,  0.0000],
+    [-0.4202,  0.1919, -0.6281,  0.7852, -0.0504, -0.0697,  0.0000,  0.0000],
+    [-0.0926,  0.0768, -0.0518,  0.0827,  1.0994, -0.2049, -0.1680, -0.1636],
+    [-0.0895, -0.2740, -0.1790, -0.3050, -0.1038,  1.2927,  0.0032, -0.0015],
+    [ 0.0000,  0.0000,  0.0000,  0.0000,  0.0084, -0.0191,  1.3382, -0.2344],
+    [ 0.0000,  0.0000,  0.0000,  0.0000, -0.0405,  0.1293, -0.2132,  1.4222],
+]
+BIAS = [-0.0095, -0.0527, -0.0642, -0.0139, -0.0410, -0.0730, -0.0624, -0.0489]
+
+
+def inverse(m):
+    n = len(m)
+    a = [row[:] + [1.0 if i == j else 0.0 for j in range(n)] for i, row in enumerate(m)]
+    for c in range(n):
+        p = max(range(c, n), key=lambda r: abs(a[r][c]))
+        a[c], a[p] = a[p], a[c]
+        a[c] = [x / a[c][c] for x in a[c]]
+        for r in range(n):
+            if r != c:
+                a[r] = [x - a[r][c] * y for x, y in zip(a[r], a[c])]
+    return [row[n:] for row in a]
+
+
+def mean_std(x):
+    mean = sum(x) / len(x)
+    return mean, math.sqrt(sum((v - mean) ** 2 for v in x) / len(x))
+
+
+def main():
+    random.seed(1)
+    inv = inverse(MATRIX)
+    # constant speed back and forth over 14 mm
+    positions = [7.0 - 14.0 * abs(2.0 * i / SAMPLES - 1.0) for i in range(SAMPLES)]
+    ideal = [[math.sqrt(2) * math.cos(p / PERIODS[c] * math.tau + PHASES[c]) for p in positions] for c in range(8)]
+
+    # the encoder normalizes each channel by its mean and std over the sweep, so the amplitudes of the compensated
+    # signals are chosen to make the normalized ones come out at 0 mean and a std of 1
+    amplitude = [1.0] * 8
+    for _ in range(50):
+        normalized = [[sum(inv[c][k] * amplitude[k] * ideal[k][i] for k in range(8)) for i in range(SAMPLES)]
+                      for c in range(8)]
+        for c in range(8):
+            amplitude[c] /= mean_std(normalized[c])[1]
+    for c in range(8):
+        mean, std = mean_std(normalized[c])
+        normalized[c] = [(v - mean) / std for v in normalized[c]]
+
+    rows = [[0.0] * SAMPLES]
+    for c in range(8):
+        rows.append([float(round(2000 + 50 * c + (600 + 20 * c) * v + random.gauss(0.0, 1.0))) for v in normalized[c]])
+
+    header = "{'descr': '<f4', 'fortran_order': False, 'shape': (9, %d), }" % SAMPLES
+    header += " " * (63 - (10 + len(header)) % 64) + "\n"
+    with open("recording.npy", "wb") as f:
+        f.write(b"\x93NUMPY\x01\x00" + struct.pack("<H", len(header)) + header.encode("latin1"))
+        for row in rows:
+            f.write(struct.pack("<%df" % SAMPLES, *row))
+
+
+if __name__ == "__main__":
+    main()
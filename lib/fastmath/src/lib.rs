@@ -0,0 +1,104 @@
+#![no_std]
+
+// trig for the control loops. libm computes these to the last bit in software, which takes a few hundred cycles each
+// on the M4F. these are short polynomials in single precision instead, with the error bounds checked by the tests
+// below against double precision
+
+#[cfg(test)]
+#[macro_use]
+extern crate std;
+
+use core::f32::consts::{FRAC_2_PI, FRAC_PI_2, PI};
+
+// pi / 2 split into parts with few enough bits that multiplying by the quadrant is exact for |quadrant| < 2^16
+const PIO2_1: f32 = 1.5703125;
+const PIO2_2: f32 = 4.837513e-4;
+const PIO2_3: f32 = 7.54979e-8;
+
+fn round(x: f32) -> i32 {
+    (if x >= 0.0 { x + 0.5 } else { x - 0.5 }) as i32
+}
+
+/// sin and cos of `x`, to within 1e-7 of the exact values for |x| < 1e4
+pub fn sincos(x: f32) -> (f32, f32) {
+    let quadrant = round(x * FRAC_2_PI);
+    let q = quadrant as f32;
+    // in [-pi / 4, pi / 4]
+    let r = ((x - q * PIO2_1) - q * PIO2_2) - q * PIO2_3;
+    let z = r * r;
+
+    let s = ((-1.9515296e-4 * z + 8.332161e-3) * z - 1.6666655e-1) * z * r + r;
+    let c = ((2.4433157e-5 * z - 1.3887316e-3) * z + 4.1666646e-2) * z * z - 0.5 * z + 1.0;
+
+    match quadrant & 3 {
+        0 => (s, c),
+        1 => (c, -s),
+        2 => (-s, -c),
+        _ => (-c, s),
+    }
+}
+
+/// angle of (x, y) in [-pi, pi], to within 6e-7 rad of the exact value
+pub fn atan2(y: f32, x: f32) -> f32 {
+    let ax = if x < 0.0 { -x } else { x };
+    let ay = if y < 0.0 { -y } else { y };
+    let swap = ay > ax;
+    let (num, den) = if swap { (ax, ay) } else { (ay, ax) };
+    if den == 0.0 {
+        return 0.0;
+    }
+
+    // minimax fit of atan over [0, 1]
+    let a = num / den;
+    let s = a * a;
+    let mut angle = ((((((0.0068117883 * s - 0.033604205) * s + 0.079623654) * s - 0.13233341) * s + 0.19807816) * s
+        - 0.3331737) * s + 0.9999961) * a;
+
+    if swap {
+        angle = FRAC_PI_2 - angle;
+    }
+    if x < 0.0 {
+        angle = PI - angle;
+    }
+    if y < 0.0 {
+        -angle
+    } else {
+        angle
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sincos() {
+        let mut max_error: f64 = 0.0;
+        for i in 0..2_000_000 {
+            let x = (i as f32 - 1e6) * 0.01;
+            let (s, c) = sincos(x);
+            max_error = max_error
+                .max((s as f64 - libm::sin(x as f64)).abs())
+                .max((c as f64 - libm::cos(x as f64)).abs());
+        }
+        assert!(max_error < 1e-7, "{}", max_error);
+        assert_eq!(sincos(0.0), (0.0, 1.0));
+    }
+
+    #[test]
+    fn test_atan2() {
+        let mut max_error: f64 = 0.0;
+        for i in 0..1_000_000 {
+            let angle = (i as f64 / 1e6 - 0.5) * 2.0 * core::f64::consts::PI;
+            // the magnitude doesn't matter, only the ratio
+            let radius = 0.1 + (i % 7) as f64;
+            let (y, x) = ((radius * libm::sin(angle)) as f32, (radius * libm::cos(angle)) as f32);
+            let error = atan2(y, x) as f64 - libm::atan2(y as f64, x as f64);
+            max_error = max_error.max(error.abs());
+        }
+        assert!(max_error < 6e-7, "{}", max_error);
+        assert_eq!(atan2(0.0, 0.0), 0.0);
+        assert_eq!(atan2(0.0, -1.0), PI);
+        assert_eq!(atan2(-1.0, 0.0), -FRAC_PI_2);
+    }
+}
@@ -1,20 +1,120 @@
 // use rtt_target::rprintln;
-use config::Config;
+use config::{storage, Config};
 use crate::open_loop_voltage::OpenLoopVoltageController;
-use crate::state_machine::{ControllerUpdate, VoltageControllerOutput};
+use crate::current_loop::CurrentCommand;
+use crate::state_machine::ControllerUpdate;
 use encoder::normalizer::{NormalizerBuilder, Normalizer};
+use encoder::compensation::{Compensation, CompensationFitter};
+use encoder::linearity::LinearityBuilder;
+use encoder::vernier::Absolute;
+use config::LINEARITY_BINS;
 use remote_obj::*;
 use bincode::{Encode, Decode};
 
-#[derive(Debug, Clone, Eq, PartialEq, RemoteGetter, RemoteSetter)]
+#[derive(Debug, Clone, Eq, PartialEq, RemoteGetter, RemoteSetter, Encode, Decode)]
 #[remote(derive(Encode, Decode, Debug))]
 pub enum EncoderCalibrationState {
     Start (u32),
     ToEndstop,
+    // back and forth over `Config::cal_pitch_cycles` to check the encoder follows the open loop drive
+    Pitch1,
+    Pitch2,
     Calib1,
     Calib2,
     Done1,
     Done2,
+    // calibration was supplied by the host, waiting for the encoder to pick it up
+    Imported,
+    // the quality checks failed, the output stays off until recalibrated
+    Failed,
+}
+
+/// result of checking the calibration against the limits in `Config`, the first failing check is reported
+#[derive(Debug, Clone, Copy, PartialEq, RemoteGetter, RemoteSetter, Encode, Decode)]
+#[remote(derive(Encode, Decode, Debug))]
+pub enum CalibrationVerdict {
+    Pending,
+    Pass,
+    TooFewSamples,
+    /// the encoder didn't move as far as the open loop motion, a stalled carriage or broken encoder
+    NotTracking,
+    /// the encoder moved the opposite way to the open loop drive, swap two phases or flip the sign of the tracks
+    Reversed,
+    /// the encoder moved a different distance per electrical cycle than `motor_len_per_cycle`, or not at all
+    PitchMismatch,
+    Noisy,
+    Hysteresis,
+    Deviation,
+    /// some bins of the linearity lookup table weren't crossed in both directions, the sweep is too short
+    Uncovered,
+}
+
+#[derive(Debug, Clone, PartialEq, RemoteGetter, RemoteSetter, Encode, Decode)]
+#[remote(derive(Encode, Decode, Debug))]
+pub struct CalibrationQuality {
+    pub samples: u32, // fewest samples in either direction
+    pub std: f32, // in mm, larger of the error spreads in each direction
+    pub hysteresis: f32, // in mm, difference between the mean errors in each direction
+    pub max_deviation: f32, // in mm, furthest the error got from the calibrated offset
+    pub travel_ratio: f32, // encoder travel over the open loop travel
+    // encoder travel over the open loop travel during the pitch check, negative if reversed. times
+    // `motor_len_per_cycle` this is the measured mm per electrical cycle
+    pub pitch_ratio: f32,
+    pub verdict: CalibrationVerdict,
+}
+
+impl CalibrationQuality {
+    pub fn new() -> CalibrationQuality {
+        CalibrationQuality {
+            samples: 0,
+            std: 0.0,
+            hysteresis: 0.0,
+            max_deviation: 0.0,
+            travel_ratio: 0.0,
+            pitch_ratio: 0.0,
+            verdict: CalibrationVerdict::Pending,
+        }
+    }
+}
+
+// least squares slope of the encoder position against the open loop position, so the constant lag of the rotor
+// behind the open loop angle drops out
+#[derive(Debug, Clone, Copy)]
+struct SlopeFit {
+    n: u32,
+    mean_x: f32,
+    mean_y: f32,
+    sxx: f32,
+    sxy: f32,
+}
+
+impl SlopeFit {
+    fn new() -> SlopeFit {
+        SlopeFit {
+            n: 0,
+            mean_x: 0.0,
+            mean_y: 0.0,
+            sxx: 0.0,
+            sxy: 0.0,
+        }
+    }
+
+    fn update(&mut self, x: f32, y: f32) {
+        self.n += 1;
+        let dx = x - self.mean_x;
+        self.mean_x += dx / self.n as f32;
+        self.mean_y += (y - self.mean_y) / self.n as f32;
+        self.sxx += dx * (x - self.mean_x);
+        self.sxy += dx * (y - self.mean_y);
+    }
+
+    fn slope(&self) -> Option<f32> {
+        if self.sxx > 0.0 {
+            Some(self.sxy / self.sxx)
+        } else {
+            None
+        }
+    }
 }
 
 #[derive(Debug, RemoteGetter, RemoteSetter)]
@@ -25,18 +125,88 @@ pub struct EncoderCalibrationController {
     pub open_loop: OpenLoopVoltageController,
     calib1_builder: NormalizerBuilder,
     calib2_builder: NormalizerBuilder,
+    travel_builder: NormalizerBuilder, // encoder positions during both sweeps
+    pub quality: CalibrationQuality,
+    // encoder against open loop position on the way back and forth of the pitch check
+    #[remote(skip)]
+    pitch_fits: [SlopeFit; 2],
+    #[remote(skip)]
+    imported: Option<EncoderCalibration>,
+    // finds the periods on the first sweep and fits the compensation matrix on the second
+    #[remote(skip)]
+    fitter: CompensationFitter,
+    #[remote(skip)]
+    compensation: Option<Compensation>,
+    #[remote(skip)]
+    linearity_builder: LinearityBuilder,
+    #[remote(skip)]
+    linearity: Option<[f32; LINEARITY_BINS]>,
 }
 
-#[derive(Debug, RemoteGetter, RemoteSetter)]
+#[derive(Debug, Clone, Copy, PartialEq, RemoteGetter, RemoteSetter, Encode, Decode)]
 #[remote(derive(Encode, Decode, Debug))]
 pub struct EncoderCalibration {
     offset: f32
 }
 
+pub const CALIBRATION_VERSION: u16 = 7;
+
+/// everything needed to skip the calibration sweep, bump the version whenever the layout or meaning changes
+#[derive(Debug, Clone, PartialEq, Encode, Decode)]
+pub struct CalibrationBlob {
+    pub version: u16,
+    pub normalizers: [Normalizer; 8],
+    pub calibration: EncoderCalibration,
+    /// `storage::encoder_hash` of the config the calibration was made with
+    pub config_hash: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Encode, Decode)]
+pub enum CalibrationError {
+    /// the blob was made by a different firmware version
+    Version,
+    /// there's no finished calibration to export yet
+    NotCalibrated,
+    /// the encoder layout, compensation or linearity correction in the config has changed since the blob was made
+    Config,
+    /// the encoder couldn't find its absolute position, so the imported offset doesn't apply
+    NotAbsolute(Absolute),
+}
+
+impl CalibrationBlob {
+    pub fn new(normalizers: [Normalizer; 8], calibration: EncoderCalibration, config: &Config) -> CalibrationBlob {
+        CalibrationBlob {
+            version: CALIBRATION_VERSION,
+            normalizers,
+            calibration,
+            config_hash: storage::encoder_hash(config),
+        }
+    }
+
+    pub fn check(&self, config: &Config) -> Result<(), CalibrationError> {
+        if self.version != CALIBRATION_VERSION {
+            return Err(CalibrationError::Version);
+        }
+        if self.config_hash != storage::encoder_hash(config) {
+            return Err(CalibrationError::Config);
+        }
+        Ok(())
+    }
+}
+
 impl EncoderCalibration {
+    pub fn new(offset: f32) -> EncoderCalibration {
+        EncoderCalibration { offset }
+    }
+
     pub fn to_angle(&self, encoder_value: f32, config: &Config) -> f32 {
         (encoder_value - self.offset) / config.motor_len_per_cycle * core::f32::consts::TAU
     }
+
+    // from mm/s to electrical radians per second
+    pub fn to_angular_velocity(&self, velocity: f32, config: &Config) -> f32 {
+        velocity / config.motor_len_per_cycle * core::f32::consts::TAU
+    }
 }
 
 impl EncoderCalibrationController {
@@ -47,15 +217,58 @@ impl EncoderCalibrationController {
             open_loop: OpenLoopVoltageController::new(),
             calib1_builder: NormalizerBuilder::new(),
             calib2_builder: NormalizerBuilder::new(),
+            travel_builder: NormalizerBuilder::new(),
+            quality: CalibrationQuality::new(),
+            pitch_fits: [SlopeFit::new(); 2],
+            imported: None,
+            fitter: CompensationFitter::new(),
+            compensation: None,
+            linearity_builder: LinearityBuilder::new(),
+            linearity: None,
         }
     }
 
+    pub fn imported(calibration: EncoderCalibration) -> EncoderCalibrationController {
+        EncoderCalibrationController {
+            state: EncoderCalibrationState::Imported,
+            imported: Some(calibration),
+            ..EncoderCalibrationController::new()
+        }
+    }
+
+    // the encoder has been set up with the imported normalizers. the imported offset is only valid if the encoder
+    // found its absolute position, otherwise positions are relative to wherever it powered up
+    pub fn import_done(&mut self, absolute: Absolute) -> Result<(), CalibrationError> {
+        if self.state != EncoderCalibrationState::Imported {
+            return Ok(());
+        }
+        if absolute != Absolute::Found {
+            return Err(CalibrationError::NotAbsolute(absolute));
+        }
+        self.state = EncoderCalibrationState::Done1;
+        Ok(())
+    }
+
+    // the compensation matrix fitted during a successful sweep, if the fit worked
+    pub fn take_compensation(&mut self) -> Option<Compensation> {
+        self.compensation.take()
+    }
+
+    // the linearity lookup table from a successful sweep
+    pub fn take_linearity(&mut self) -> Option<[f32; LINEARITY_BINS]> {
+        self.linearity.take()
+    }
+
     pub fn encoder_ready(&self) -> bool {
         match self.state {
+            EncoderCalibrationState::Pitch1 |
+            EncoderCalibrationState::Pitch2 |
             EncoderCalibrationState::Calib1 |
             EncoderCalibrationState::Calib2 |
             EncoderCalibrationState::Done1 |
-            EncoderCalibrationState::Done2 => {
+            EncoderCalibrationState::Done2 |
+            EncoderCalibrationState::Imported |
+            EncoderCalibrationState::Failed => {
                 true
             }
             _ => {
@@ -86,13 +299,82 @@ impl EncoderCalibrationController {
         }
     }
 
-    pub fn get_calib(&self) -> Option<EncoderCalibration> {
-        let [l, r] = self.get_calib_raw()?;
+    // the verdict stays pending if the pitch check passed, the sweep decides the rest
+    fn check_pitch(&self, config: &Config) -> CalibrationQuality {
+        let mut quality = CalibrationQuality::new();
+        quality.samples = self.pitch_fits[0].n.min(self.pitch_fits[1].n);
+
+        let ratio = match self.pitch_fits.map(|fit| fit.slope()) {
+            [Some(back), Some(forth)] if quality.samples >= config.cal_min_samples => (back + forth) / 2.0,
+            _ => {
+                quality.verdict = CalibrationVerdict::TooFewSamples;
+                return quality;
+            }
+        };
+
+        quality.pitch_ratio = ratio;
+        quality.verdict = if (ratio + 1.0).abs() <= config.cal_max_pitch_error {
+            CalibrationVerdict::Reversed
+        } else if (ratio - 1.0).abs() > config.cal_max_pitch_error {
+            CalibrationVerdict::PitchMismatch
+        } else {
+            CalibrationVerdict::Pending
+        };
+        quality
+    }
+
+    pub fn get_quality(&self, config: &Config) -> CalibrationQuality {
+        let mut quality = CalibrationQuality::new();
+        quality.pitch_ratio = self.quality.pitch_ratio;
+        quality.samples = self.calib1_builder.count().min(self.calib2_builder.count());
+
+        let (l, r) = match self.get_calib_raw() {
+            Some([l, r]) if quality.samples >= config.cal_min_samples => (l, r),
+            _ => {
+                quality.verdict = CalibrationVerdict::TooFewSamples;
+                return quality;
+            }
+        };
+
         let offset = (l.mean + r.mean) / 2.0;
+        let deviation = |builder: &NormalizerBuilder| {
+            let (min, max) = builder.range();
+            (max - offset).max(offset - min)
+        };
+        let (travel_min, travel_max) = self.travel_builder.range();
+
+        quality.std = l.std.max(r.std);
+        quality.hysteresis = (l.mean - r.mean).abs();
+        quality.max_deviation = deviation(&self.calib1_builder).max(deviation(&self.calib2_builder));
+        quality.travel_ratio = (travel_max - travel_min) / config.calibration_length;
+
+        quality.verdict = if (quality.travel_ratio - 1.0).abs() > config.cal_max_travel_error {
+            CalibrationVerdict::NotTracking
+        } else if quality.std > config.cal_max_std {
+            CalibrationVerdict::Noisy
+        } else if quality.hysteresis > config.cal_max_hysteresis {
+            CalibrationVerdict::Hysteresis
+        } else if quality.max_deviation > config.cal_max_deviation {
+            CalibrationVerdict::Deviation
+        } else if self.linearity_builder.result().is_none() {
+            CalibrationVerdict::Uncovered
+        } else {
+            CalibrationVerdict::Pass
+        };
+        quality
+    }
+
+    pub fn get_calib(&self) -> Option<EncoderCalibration> {
+        if let Some(imported) = self.imported {
+            return Some(imported);
+        }
+        // the mean error without any linearity correction, which goes with the new lookup table. a sweep that
+        // couldn't build one has failed
+        let (offset, _) = self.linearity_builder.result()?;
         Some(EncoderCalibration {offset})
     }
 
-    pub fn update(&mut self, update: &ControllerUpdate, config: &Config) -> VoltageControllerOutput {
+    pub fn update(&mut self, update: &ControllerUpdate, config: &Config) -> CurrentCommand {
         let dir;
         let cal_length = config.calibration_length / config.motor_len_per_cycle // calibration length in motor cycles
                             * core::f32::consts::TAU;
@@ -102,7 +384,8 @@ impl EncoderCalibrationController {
         match &mut self.state {
             EncoderCalibrationState::Start(wait) => {
                 *wait += 1;
-                if *wait > 1_000 {
+                // let the open loop voltage settle the rotor before moving
+                if *wait as f32 * config.motion_sample_time() > 0.125 {
                     self.state = EncoderCalibrationState::ToEndstop;
                     self.position_target = cal_length;
                 }
@@ -110,19 +393,51 @@ impl EncoderCalibrationController {
             }
             EncoderCalibrationState::ToEndstop => {
                 if self.open_loop.position > self.position_target {
-                    self.state = EncoderCalibrationState::Calib1;
-                    self.position_target = 0.0;
+                    if config.cal_pitch_cycles > 0.0 {
+                        self.state = EncoderCalibrationState::Pitch1;
+                        self.position_target = cal_length - config.cal_pitch_cycles * core::f32::consts::TAU;
+                    } else {
+                        self.state = EncoderCalibrationState::Calib1;
+                        self.position_target = 0.0;
+                    }
                 }
                 dir = 1.0;
             }
+            EncoderCalibrationState::Pitch1 |
+            EncoderCalibrationState::Pitch2 => {
+                let forward = self.state == EncoderCalibrationState::Pitch2;
+                if let Some(output) = update.position.as_ref() {
+                    self.pitch_fits[forward as usize].update(self.open_loop.get_position(&config), output.position);
+                }
+                if forward {
+                    // back where the pitch check started, so the sweep covers the same length as without it
+                    if self.open_loop.position > self.position_target {
+                        self.quality = self.check_pitch(config);
+                        self.state = match self.quality.verdict {
+                            CalibrationVerdict::Pending => EncoderCalibrationState::Calib1,
+                            _ => EncoderCalibrationState::Failed,
+                        };
+                        self.position_target = 0.0;
+                    }
+                    dir = 1.0;
+                } else {
+                    if self.open_loop.position < self.position_target {
+                        self.state = EncoderCalibrationState::Pitch2;
+                        self.position_target = cal_length;
+                    }
+                    dir = -1.0;
+                }
+            }
             state @ EncoderCalibrationState::Calib1 |
             state @ EncoderCalibrationState::Calib2 => {
                 // get open loop position request and encoder position in units of mm
-                let position = update.position.as_ref().unwrap().position;
+                let output = update.position.as_ref().unwrap();
+                let position = output.position;
                 let position_target = self.open_loop.get_position(&config);
 
                 let error = position - position_target;
                 let norm_builder;
+                let forward;
                 match state {
                      EncoderCalibrationState::Calib1 => {
                          if self.open_loop.position < self.position_target {
@@ -130,19 +445,35 @@ impl EncoderCalibrationController {
                              self.position_target = cal_length;
                          }
                          dir = -1.0;
+                         forward = false;
                          norm_builder = &mut self.calib1_builder;
+                         self.fitter.update_periods(&output.normalized, position_target);
                      }
                     EncoderCalibrationState::Calib2 => {
                         if self.open_loop.position > self.position_target {
-                            self.state = EncoderCalibrationState::Done1;
+                            self.quality = self.get_quality(config);
+                            self.state = match self.quality.verdict {
+                                CalibrationVerdict::Pass => {
+                                    self.compensation = self.fitter.fit();
+                                    self.linearity = self.linearity_builder.result().map(|(_, lut)| lut);
+                                    EncoderCalibrationState::Done1
+                                }
+                                _ => EncoderCalibrationState::Failed,
+                            };
                             self.position_target = 0.0;
                         }
                         dir = 1.0;
+                        forward = true;
                         norm_builder = &mut self.calib2_builder;
+                        self.fitter.update_fit(&output.normalized, position_target);
                     }
                     _ => {unreachable!()}
                 }
                 norm_builder.update(error);
+                self.travel_builder.update(position);
+                // add back the correction from the old table, the new one replaces it. like the offset, the table is
+                // measured with the compensation in use during the sweep
+                self.linearity_builder.update(forward, output.phase, error + output.linearity);
             }
             EncoderCalibrationState::Done1 => {
                 if self.open_loop.position < self.position_target {
@@ -158,12 +489,118 @@ impl EncoderCalibrationController {
                 }
                 dir = 1.0;
             }
+            EncoderCalibrationState::Imported |
+            EncoderCalibrationState::Failed => {
+                dir = 0.0;
+            }
         }
 
-        let mut v_out = self.open_loop.process_velocity(0.0005 * dir, update, config);
-        if dir == 0.0 {
-            v_out.driver_enable = false;
+        let command = self.open_loop.process_velocity(config.calibration_speed * core::f32::consts::TAU * dir, update, config);
+        match self.state {
+            // whatever ran before might have left the motor moving, the open loop drive couldn't pick it up
+            EncoderCalibrationState::Start(_) => CurrentCommand::Idle(true),
+            _ if dir == 0.0 => CurrentCommand::Idle(false),
+            _ => command,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // errors alternating around `mean`, with positions covering the whole calibration length
+    fn sweep(cal: &mut EncoderCalibrationController, config: &Config, samples: u32, mean1: f32, mean2: f32) {
+        for i in 0..samples {
+            let noise = if i % 2 == 0 { 0.1 } else { -0.1 };
+            let position = i as f32 / samples as f32 * config.calibration_length;
+            let phase = (i as f32 * 0.0137) % 1.0;
+            cal.calib1_builder.update(mean1 + noise);
+            cal.calib2_builder.update(mean2 + noise);
+            cal.travel_builder.update(position);
+            cal.linearity_builder.update(false, phase, mean1 + noise);
+            cal.linearity_builder.update(true, phase, mean2 + noise);
+        }
+    }
+
+    #[test]
+    fn test_quality() {
+        let config = Config::new();
+
+        let mut cal = EncoderCalibrationController::new();
+        sweep(&mut cal, &config, 10_000, 1.2, 1.0);
+        let quality = cal.get_quality(&config);
+        assert_eq!(quality.verdict, CalibrationVerdict::Pass);
+        assert!((quality.hysteresis - 0.2).abs() < 1e-3);
+        assert!((quality.max_deviation - 0.2).abs() < 1e-3);
+        assert!((quality.travel_ratio - 1.0).abs() < 1e-3);
+        assert!((cal.get_calib().unwrap().offset - 1.1).abs() < 1e-3);
+
+        let mut cal = EncoderCalibrationController::new();
+        sweep(&mut cal, &config, 100, 1.2, 1.0);
+        assert_eq!(cal.get_quality(&config).verdict, CalibrationVerdict::TooFewSamples);
+
+        let mut cal = EncoderCalibrationController::new();
+        sweep(&mut cal, &config, 10_000, 4.0, 1.0);
+        assert_eq!(cal.get_quality(&config).verdict, CalibrationVerdict::Hysteresis);
+
+        let mut cal = EncoderCalibrationController::new();
+        sweep(&mut cal, &config, 10_000, 1.2, 1.0);
+        cal.travel_builder = NormalizerBuilder::new();
+        cal.travel_builder.update(0.0);
+        cal.travel_builder.update(1.0);
+        assert_eq!(cal.get_quality(&config).verdict, CalibrationVerdict::NotTracking);
+
+        // and there's no offset to fall back on
+        let mut cal = EncoderCalibrationController::new();
+        sweep(&mut cal, &config, 10_000, 1.2, 1.0);
+        cal.linearity_builder = LinearityBuilder::new();
+        assert_eq!(cal.get_quality(&config).verdict, CalibrationVerdict::Uncovered);
+        assert_eq!(cal.get_calib(), None);
+    }
+
+    // encoder positions following the open loop back and forth with a lag, at `ratio` times the open loop travel
+    fn pitch_check(config: &Config, ratio: f32) -> CalibrationQuality {
+        let mut cal = EncoderCalibrationController::new();
+        let length = config.cal_pitch_cycles * config.motor_len_per_cycle;
+        for i in 0..2000 {
+            let x = length - i as f32 / 2000.0 * length;
+            cal.pitch_fits[0].update(x, ratio * (x + 0.5) + 3.0);
+            cal.pitch_fits[1].update(length - x, ratio * (length - x - 0.5) + 3.0);
         }
-        v_out
+        cal.check_pitch(config)
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_pitch() {
+        let config = Config::new();
+
+        let quality = pitch_check(&config, 1.02);
+        assert_eq!(quality.verdict, CalibrationVerdict::Pending);
+        assert!((quality.pitch_ratio - 1.02).abs() < 1e-3, "{:?}", quality);
+
+        assert_eq!(pitch_check(&config, -1.0).verdict, CalibrationVerdict::Reversed);
+        assert_eq!(pitch_check(&config, 2.34375 / 19.0).verdict, CalibrationVerdict::PitchMismatch);
+        assert_eq!(pitch_check(&config, 0.0).verdict, CalibrationVerdict::PitchMismatch);
+
+        let cal = EncoderCalibrationController::new();
+        assert_eq!(cal.check_pitch(&config).verdict, CalibrationVerdict::TooFewSamples);
+    }
+
+    #[test]
+    fn test_import() {
+        let mut config = Config::new();
+        let blob = CalibrationBlob::new([Normalizer { mean: 0.0, std: 1.0 }; 8], EncoderCalibration::new(1.0), &config);
+        assert_eq!(blob.check(&config), Ok(()));
+
+        // the blob doesn't apply to a different encoder setup
+        config.comp_bias[3] += 0.01;
+        assert_eq!(blob.check(&config), Err(CalibrationError::Config));
+
+        let mut cal = EncoderCalibrationController::imported(blob.calibration);
+        assert_eq!(cal.import_done(Absolute::Ambiguous), Err(CalibrationError::NotAbsolute(Absolute::Ambiguous)));
+        assert_eq!(cal.state, EncoderCalibrationState::Imported);
+        assert_eq!(cal.import_done(Absolute::Found), Ok(()));
+        assert_eq!(cal.state, EncoderCalibrationState::Done1);
+    }
+}
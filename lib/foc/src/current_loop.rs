@@ -0,0 +1,351 @@
+// the part of the controller that runs at the full ADC rate. the motion loop runs less often and hands it a command,
+// which it keeps following until the next one arrives
+use config::Config;
+use crate::pid::DQCurrentController;
+use crate::state_machine::{ControllerUpdate, PWMCommand, VoltageControllerOutput};
+use crate::svm::IterativeSVM;
+use crate::transforms::{AlphaBetaVoltages, DQCurrents, SinCos};
+use remote_obj::*;
+use bincode::{Encode, Decode};
+
+/// the parts of the config the current loop needs. the config belongs to the motion loop, so these are copied over
+/// with every command
+#[derive(Debug, Clone, Copy, PartialEq, RemoteGetter, RemoteSetter)]
+#[remote(derive(Encode, Decode, Debug))]
+pub struct CurrentParams {
+    pub k_p: f32,
+    pub k_i: f32, // per current loop iteration
+    pub sample_time: f32, // in seconds
+    pub angle_delay: f32, // in seconds
+    pub motion_divider: u32,
+    pub uvlo: f32, // in volts
+    pub hard_curr_limit: f32, // in amps
+}
+
+impl CurrentParams {
+    pub fn new(config: &Config) -> CurrentParams {
+        CurrentParams {
+            k_p: config.current_controller_k_p,
+            k_i: config.current_controller_k_i * config.sample_time(),
+            sample_time: config.sample_time(),
+            angle_delay: config.angle_delay,
+            motion_divider: config.motion_divider.max(1),
+            uvlo: config.uvlo,
+            hard_curr_limit: config.hard_curr_limit,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, RemoteGetter, RemoteSetter)]
+#[remote(derive(Encode, Decode, Debug))]
+pub struct CurrentSetpoint {
+    pub d: f32, // in amps
+    pub q: f32,
+    pub angle: f32, // electrical, at the sample the position was taken from
+    pub velocity: f32, // in electrical radians per second
+    pub sample: u32, // current loop iteration the position was taken from
+}
+
+#[derive(Debug, Clone, PartialEq, RemoteGetter, RemoteSetter)]
+#[remote(derive(Encode, Decode, Debug))]
+pub enum CurrentCommand {
+    /// output stage off, with the phases shorted together if set
+    Idle(bool),
+    /// fixed voltage vector, for the open loop calibration and manual output
+    Voltage(VoltageControllerOutput),
+    /// duty cycle of each half bridge from 0 to 1, for manual output
+    Duty([f32; 3]),
+    /// closed loop current, with the angle extrapolated from when the position or open loop angle was sampled
+    Current(CurrentSetpoint),
+}
+
+/// why the current loop turned the output stage off by itself, see `CurrentLoop::take_fault`
+#[derive(Debug, Clone, Copy, PartialEq, Encode, Decode)]
+pub enum CurrentFault {
+    /// the bus voltage is below `Config::uvlo`
+    Undervoltage,
+    /// a phase current is above `Config::hard_curr_limit`
+    Overcurrent,
+}
+
+/// what the current loop wants from the output stage, before the SVM turns it into duty cycles
+#[derive(Debug, Clone, PartialEq)]
+pub enum Modulation {
+    Idle(bool),
+    Voltage(VoltageControllerOutput),
+    Duty([f32; 3]),
+}
+
+#[derive(Debug, Clone, RemoteGetter, RemoteSetter)]
+#[remote(derive(Encode, Decode, Debug))]
+pub struct CurrentLoop {
+    command: CurrentCommand,
+    params: CurrentParams,
+    current_controller: DQCurrentController,
+    svm: IterativeSVM,
+    dq_currents: DQCurrents,
+    voltage: AlphaBetaVoltages, // last applied, in volts
+    duty: [u16; 3],
+    sample: u32, // of the last update
+    // the output stays off from a fault until the motion loop has taken it and sent a command with it in mind
+    #[remote(skip)]
+    fault: Option<CurrentFault>,
+    #[remote(skip)]
+    fault_taken: bool,
+}
+
+impl CurrentLoop {
+    pub fn new(config: &Config) -> CurrentLoop {
+        let cycle_time = config.switching_clock_frequency / config.switching_frequency;
+        let dead_time_cycles = config.cycle_deadtime * config.switching_clock_frequency;
+        let params = CurrentParams::new(config);
+
+        CurrentLoop {
+            command: CurrentCommand::Idle(false),
+            params,
+            current_controller: DQCurrentController::new(&params),
+            svm: IterativeSVM::new(dead_time_cycles as u16, cycle_time as u16),
+            dq_currents: DQCurrents::default(),
+            voltage: AlphaBetaVoltages::default(),
+            duty: [0; 3],
+            sample: 0,
+            fault: None,
+            fault_taken: false,
+        }
+    }
+
+    /// the motion loop should run on iterations that are a multiple of this
+    pub fn motion_divider(&self) -> u32 {
+        self.params.motion_divider
+    }
+
+    pub fn duty(&self) -> &[u16; 3] {
+        &self.duty
+    }
+
+    /// a fault since the last call, for the motion loop to stop the controller with
+    pub fn take_fault(&mut self) -> Option<CurrentFault> {
+        if self.fault_taken {
+            return None;
+        }
+        self.fault_taken = true;
+        self.fault
+    }
+
+    pub fn set_command(&mut self, command: CurrentCommand, config: &Config) {
+        self.params = CurrentParams::new(config);
+        if self.fault_taken {
+            self.fault = None;
+        }
+
+        // the integrators are preloaded so the output continues from whatever was applied until now
+        if let CurrentCommand::Current(setpoint) = &command {
+            if !matches!(self.command, CurrentCommand::Current(_)) {
+                let angle = SinCos::new(self.angle(setpoint, 0.0));
+                self.current_controller.preload(&self.voltage.park_transform(&angle), &self.params);
+            }
+        }
+        self.command = command;
+    }
+
+    // electrical angle `delay` seconds after the current sample
+    fn angle(&self, setpoint: &CurrentSetpoint, delay: f32) -> f32 {
+        let elapsed = self.sample.wrapping_sub(setpoint.sample) as f32 * self.params.sample_time;
+        setpoint.angle + setpoint.velocity * (elapsed + delay)
+    }
+
+    /// `update` doesn't need a position, the current loop only uses the currents and bus voltage
+    pub fn update(&mut self, update: &ControllerUpdate) -> PWMCommand {
+        let modulation = self.control(update);
+        self.modulate(modulation)
+    }
+
+    /// `update` without the SVM, split up so the two can be timed separately
+    pub fn control(&mut self, update: &ControllerUpdate) -> Modulation {
+        self.sample = update.sample;
+
+        // checked here rather than in the motion loop, which can be several samples away
+        let fault = if !(update.bus_voltage >= self.params.uvlo) {
+            Some(CurrentFault::Undervoltage)
+        } else if !(update.phase_currents.max_magnitude() <= self.params.hard_curr_limit) {
+            Some(CurrentFault::Overcurrent)
+        } else {
+            None
+        };
+        if fault.is_some() {
+            self.fault = fault;
+            self.fault_taken = false;
+        }
+        if self.fault.is_some() {
+            self.voltage = AlphaBetaVoltages::default();
+            return Modulation::Idle(false);
+        }
+
+        match &self.command {
+            CurrentCommand::Idle(brake) => {
+                self.voltage = AlphaBetaVoltages::default();
+                Modulation::Idle(*brake)
+            }
+            CurrentCommand::Voltage(output) => {
+                self.voltage = AlphaBetaVoltages {
+                    alpha: output.alpha * update.bus_voltage,
+                    beta: output.beta * update.bus_voltage,
+                };
+                Modulation::Voltage(output.clone())
+            }
+            // manual output always goes through the calibration before closed loop current, so there's nothing to
+            // hand over from here
+            CurrentCommand::Duty(duty) => {
+                self.voltage = AlphaBetaVoltages::default();
+                Modulation::Duty(*duty)
+            }
+            CurrentCommand::Current(setpoint) => {
+                let dq_currents = update.phase_currents
+                    .clarke_transform()
+                    .park_transform(&SinCos::new(self.angle(setpoint, 0.0)));
+
+                // the voltage only gets applied later, by which time the motor has moved on
+                let output_angle = SinCos::new(self.angle(setpoint, self.params.angle_delay));
+
+                let voltage_request = self.current_controller.update(
+                    &dq_currents,
+                    &DQCurrents {
+                        d: setpoint.d,
+                        q: setpoint.q,
+                    },
+                    &self.params);
+
+                self.dq_currents = dq_currents;
+                self.voltage = voltage_request.inv_park_transform(&output_angle);
+                Modulation::Voltage(self.voltage.to_voltage_controller_output(update))
+            }
+        }
+    }
+
+    pub fn modulate(&mut self, modulation: Modulation) -> PWMCommand {
+        let pwm = match modulation {
+            Modulation::Idle(brake) => PWMCommand::idle(brake),
+            Modulation::Voltage(output) => self.svm.calculate(output),
+            Modulation::Duty(duty) => self.svm.duty(duty),
+        };
+        self.duty = pwm.to_array();
+        pwm
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::open_loop_voltage::OpenLoopVoltageController;
+    use crate::transforms::PhaseCurrents;
+    use config::OpenLoopMode;
+
+    fn update(sample: u32) -> ControllerUpdate {
+        ControllerUpdate {
+            phase_currents: PhaseCurrents { u: 0.0, v: 0.0, w: 0.0 },
+            bus_voltage: 24.0,
+            position: None,
+            sample,
+            fault: None,
+        }
+    }
+
+    #[test]
+    fn test_handover() {
+        let config = Config::new();
+        let mut current_loop = CurrentLoop::new(&config);
+
+        current_loop.set_command(CurrentCommand::Voltage(VoltageControllerOutput {
+            driver_enable: true,
+            alpha: 0.1,
+            beta: 0.2,
+        }), &config);
+        current_loop.update(&update(1));
+
+        // with no current error the output carries on from the open loop voltage
+        current_loop.set_command(CurrentCommand::Current(CurrentSetpoint {
+            d: 0.0,
+            q: 0.0,
+            angle: 1.0,
+            velocity: 0.0,
+            sample: 1,
+        }), &config);
+        current_loop.update(&update(2));
+        assert!((current_loop.voltage.alpha - 2.4).abs() < 1e-4, "{:?}", current_loop.voltage);
+        assert!((current_loop.voltage.beta - 4.8).abs() < 1e-4, "{:?}", current_loop.voltage);
+
+        current_loop.set_command(CurrentCommand::Idle(true), &config);
+        assert_eq!(current_loop.update(&update(3)), PWMCommand::idle(true));
+    }
+
+    #[test]
+    fn test_fault() {
+        let config = Config::new();
+        let mut current_loop = CurrentLoop::new(&config);
+        let command = CurrentCommand::Voltage(VoltageControllerOutput {
+            driver_enable: true,
+            alpha: 0.1,
+            beta: 0.2,
+        });
+        current_loop.set_command(command.clone(), &config);
+
+        let mut overcurrent = update(1);
+        overcurrent.phase_currents = PhaseCurrents { u: 2.0 * config.hard_curr_limit, v: 0.0, w: 0.0 };
+        assert_eq!(current_loop.control(&overcurrent), Modulation::Idle(false));
+
+        // stays off after the current is back, until the motion loop has seen the fault and sent a new command
+        assert_eq!(current_loop.control(&update(2)), Modulation::Idle(false));
+        current_loop.set_command(command.clone(), &config);
+        assert_eq!(current_loop.control(&update(3)), Modulation::Idle(false));
+        assert_eq!(current_loop.take_fault(), Some(CurrentFault::Overcurrent));
+        assert_eq!(current_loop.take_fault(), None);
+        current_loop.set_command(command, &config);
+        assert!(matches!(current_loop.control(&update(4)), Modulation::Voltage(_)));
+
+        let mut undervoltage = update(5);
+        undervoltage.bus_voltage = 0.5 * config.uvlo;
+        assert_eq!(current_loop.control(&undervoltage), Modulation::Idle(false));
+        assert_eq!(current_loop.take_fault(), Some(CurrentFault::Undervoltage));
+    }
+
+    #[test]
+    fn test_extrapolation() {
+        let config = Config::new();
+        let mut current_loop = CurrentLoop::new(&config);
+        let setpoint = CurrentSetpoint {
+            d: 0.0,
+            q: 1.0,
+            angle: 1.0,
+            velocity: 100.0,
+            sample: 10,
+        };
+        current_loop.set_command(CurrentCommand::Current(setpoint.clone()), &config);
+
+        // the motion loop can be late, the angle follows the samples rather than the handover
+        current_loop.update(&update(14));
+        let elapsed = 4.0 * config.sample_time();
+        assert!((current_loop.angle(&setpoint, 0.0) - (1.0 + 100.0 * elapsed)).abs() < 1e-6);
+        let delayed = current_loop.angle(&setpoint, config.angle_delay);
+        assert!((delayed - (1.0 + 100.0 * (elapsed + config.angle_delay))).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_open_loop_current() {
+        let mut config = Config::new();
+        let mut open_loop = OpenLoopVoltageController::new();
+        let voltage = match open_loop.process_position(1.0, &update(0), &config) {
+            CurrentCommand::Voltage(output) => output,
+            command => panic!("{:?}", command),
+        };
+
+        // with no current flowing yet, the current controller pushes the same way as the open loop voltage
+        config.open_loop_mode = OpenLoopMode::Current;
+        let mut current_loop = CurrentLoop::new(&config);
+        current_loop.set_command(open_loop.process_position(1.0, &update(0), &config), &config);
+        current_loop.update(&update(0));
+        let v = &current_loop.voltage;
+        let cross = v.alpha * voltage.beta - v.beta * voltage.alpha;
+        let dot = v.alpha * voltage.alpha + v.beta * voltage.beta;
+        assert!(dot > 0.0 && cross.abs() < 1e-3 * dot, "{:?} {:?}", v, voltage);
+    }
+}
@@ -1,11 +1,24 @@
 use crate::calibration::EncoderCalibration;
 use config::Config;
-use crate::pid::{DQCurrentController, PController, PIController};
-use crate::state_machine::{ControllerUpdate, VoltageControllerOutput};
-use crate::transforms::DQCurrents;
+use crate::current_loop::{CurrentCommand, CurrentSetpoint};
+use crate::pid::{PController, PIController};
+use crate::state_machine::ControllerUpdate;
+use crate::transforms::SinCos;
+use crate::motion::MotionCommand;
 use remote_obj::*;
 use bincode::{Encode, Decode};
 use encoder::EncoderOutput;
+#[allow(unused_imports)]
+use micromath::F32Ext;
+
+#[derive(Debug, Clone, Copy, PartialEq, RemoteGetter, RemoteSetter)]
+#[remote(derive(Encode, Decode, Debug))]
+pub enum ControlMode {
+    Position,
+    Velocity,
+    Force,
+    Impedance,
+}
 
 #[derive(Debug, RemoteGetter, RemoteSetter)]
 #[remote(derive(Encode, Decode, Debug))]
@@ -13,19 +26,70 @@ pub struct PosController {
     pub vel_controller: PIController,
     pub pos_controller: PController,
 
+    pub mode: ControlMode,
     pub pos_setpoint: f32,
     pub vel_setpoint: f32,
+    pub vel_feedforward: f32, // in mm/s
+    pub curr_feedforward: f32, // in amps
+    pub stiffness: f32, // in N/mm, only used in impedance mode
+    pub damping: f32, // in N/(mm/s), only used in impedance mode
+
+    settled_time: f32, // in seconds
+    #[remote(skip)]
+    last_pos_setpoint: f32,
 }
 
 impl PosController {
-    fn update(&mut self, encoder: &EncoderOutput, saturated: bool, config: &Config) -> f32 {
-        self.vel_controller.k_i = config.vel_controller_k_i;
+    fn update_gains(&mut self, config: &Config) {
+        self.vel_controller.k_i = config.vel_controller_k_i * config.motion_sample_time();
         self.vel_controller.p_controller.k_p = config.vel_controller_k_p;
         self.pos_controller.k_p = config.pos_controller_k_p;
+    }
+
+    fn update(&mut self, encoder: &EncoderOutput, saturated: bool, config: &Config) -> f32 {
+        self.update_gains(config);
+
+        match self.mode {
+            ControlMode::Position => {
+                let velocity_setpoint = self.pos_controller.update(self.pos_setpoint - encoder.filtered_position);
+                self.vel_setpoint = velocity_setpoint + self.vel_feedforward;
+                self.vel_controller.update(encoder.velocity - self.vel_setpoint, saturated) + self.curr_feedforward
+            }
+            ControlMode::Velocity => {
+                self.vel_setpoint = self.vel_feedforward;
+                self.vel_controller.update(encoder.velocity - self.vel_setpoint, saturated) + self.curr_feedforward
+            }
+            ControlMode::Force => {
+                self.curr_feedforward
+            }
+            ControlMode::Impedance => {
+                let force = self.stiffness * (self.pos_setpoint - encoder.filtered_position) - self.damping * encoder.velocity;
+                force / config.force_constant + self.curr_feedforward
+            }
+        }
+    }
 
-        let velocity_setpoint = self.pos_controller.update(self.pos_setpoint - encoder.filtered_position);
-        self.vel_setpoint = velocity_setpoint;
-        return self.vel_controller.update(encoder.velocity - self.vel_setpoint, saturated);
+    // latches once the position has been within tolerance for long enough, until the setpoint changes
+    fn update_settled(&mut self, encoder: &EncoderOutput, config: &Config) -> bool {
+        if self.mode != ControlMode::Position {
+            self.settled_time = 0.0;
+            return false;
+        }
+
+        if self.pos_setpoint != self.last_pos_setpoint {
+            self.last_pos_setpoint = self.pos_setpoint;
+            self.settled_time = 0.0;
+        }
+
+        if self.settled_time < config.settle_time {
+            if (self.pos_setpoint - encoder.filtered_position).abs() < config.settle_tolerance {
+                self.settled_time += config.motion_sample_time();
+            } else {
+                self.settled_time = 0.0;
+            }
+        }
+
+        self.settled_time >= config.settle_time
     }
 }
 
@@ -34,66 +98,160 @@ impl PosController {
 #[remote(derive(Encode, Decode, Debug))]
 pub struct FieldOrientedControl {
     cal: EncoderCalibration,
-    current_controller: DQCurrentController,
-    dq_currents: DQCurrents,
     q_req: f32,
     #[remote(skip)]
     saturated: bool,
     pos_controller: PosController,
     encoder_output: EncoderOutput,
+    curr_limit: f32, // ramps up to config.curr_limit after engaging
+    #[remote(skip)]
+    hold: bool,
 }
 
 impl FieldOrientedControl {
     pub fn new(cal: EncoderCalibration, config: &Config) -> FieldOrientedControl {
         FieldOrientedControl {
             cal,
-            current_controller: DQCurrentController::new(config),
-            dq_currents: DQCurrents::default(),
             q_req: 0.0,
             saturated: false,
             pos_controller: PosController {
-                vel_controller: PIController::new(config.vel_controller_k_i, config.vel_controller_k_p),
+                vel_controller: PIController::new(config.vel_controller_k_i * config.motion_sample_time(), config.vel_controller_k_p),
                 pos_controller: PController::new(config.pos_controller_k_p),
-                pos_setpoint: -50.0,
-                vel_setpoint: 0.0
+                mode: ControlMode::Position,
+                pos_setpoint: 0.0,
+                vel_setpoint: 0.0,
+                vel_feedforward: 0.0,
+                curr_feedforward: 0.0,
+                stiffness: 0.0,
+                damping: 0.0,
+                settled_time: 0.0,
+                last_pos_setpoint: 0.0,
             },
             encoder_output: EncoderOutput::default(),
+            curr_limit: 0.0,
+            hold: false,
         }
     }
 
-    pub fn update(&mut self, update: &ControllerUpdate, config: &Config) -> VoltageControllerOutput {
-        // encoder output is in terms of mm
+    // called whenever the output starts being driven by this controller. the velocity integrator is preloaded with
+    // the current that is flowing so the output continues from there, and the current limit ramps up from zero. the
+    // current loop does the same for its own integrators
+    pub fn engage(&mut self, update: &ControllerUpdate, reset_setpoint: bool, config: &Config) {
         let encoder_output = update.position.as_ref().unwrap();
-        self.encoder_output = encoder_output.clone();
-        let angle = self.cal.to_angle(self.encoder_output.position, config);
+        let angle = self.cal.to_angle(encoder_output.position, config);
 
         let dq_currents = update.phase_currents
             .clarke_transform()
-            .park_transform(angle);
+            .park_transform(&SinCos::new(angle));
 
-        // let voltage_request = DQVoltages {
-        //      d: 0.0,
-        //      q: config.open_loop_voltage
-        // };
+        self.pos_controller.update_gains(config);
+        self.pos_controller.vel_controller.preload(dq_currents.q);
 
-        let q = self.pos_controller.update(encoder_output, self.saturated, config);
-        let q = q.max(-config.curr_limit).min(config.curr_limit);
+        if reset_setpoint {
+            self.pos_controller.mode = ControlMode::Position;
+            self.pos_controller.pos_setpoint = encoder_output.filtered_position;
+        }
+
+        self.curr_limit = 0.0;
+        self.saturated = false;
+    }
 
-        self.saturated = q == config.curr_limit || q == -config.curr_limit;
+    pub fn calibration(&self) -> EncoderCalibration {
+        self.cal
+    }
 
-        let voltage_request = self.current_controller.update(
-            &dq_currents,
-            &DQCurrents{
-                d: 0.0,
-                q,
-            },
-            &config);
+    pub fn move_done(&mut self, update: &ControllerUpdate, config: &Config) -> bool {
+        self.pos_controller.update_settled(update.position.as_ref().unwrap(), config)
+    }
+
+    // while holding, the current is limited to `hold_curr_limit`
+    pub fn set_hold(&mut self, hold: bool) {
+        self.hold = hold;
+    }
+
+    pub fn hold_position(&mut self, update: &ControllerUpdate) {
+        self.pos_controller.mode = ControlMode::Position;
+        self.pos_controller.pos_setpoint = update.position.as_ref().unwrap().filtered_position;
+        self.clear_feedforward();
+    }
+
+    pub fn set_setpoint(&mut self, position: f32, velocity: f32, current: f32) {
+        self.pos_controller.mode = ControlMode::Position;
+        self.pos_controller.pos_setpoint = position;
+        self.pos_controller.vel_feedforward = velocity;
+        self.pos_controller.curr_feedforward = current;
+    }
+
+    // setpoints are checked against the configured limits before this is called
+    pub fn command(&mut self, command: &MotionCommand, config: &Config) {
+        let pos_controller = &mut self.pos_controller;
+        match *command {
+            MotionCommand::Position(position) => {
+                self.set_setpoint(position, 0.0, 0.0);
+            }
+            MotionCommand::Velocity(velocity) => {
+                pos_controller.mode = ControlMode::Velocity;
+                pos_controller.vel_feedforward = velocity;
+                pos_controller.curr_feedforward = 0.0;
+            }
+            MotionCommand::Force(force) => {
+                pos_controller.mode = ControlMode::Force;
+                pos_controller.vel_feedforward = 0.0;
+                pos_controller.curr_feedforward = force / config.force_constant;
+            }
+            MotionCommand::Impedance { position, stiffness, damping } => {
+                pos_controller.mode = ControlMode::Impedance;
+                pos_controller.pos_setpoint = position;
+                pos_controller.stiffness = stiffness;
+                pos_controller.damping = damping;
+                pos_controller.vel_feedforward = 0.0;
+                pos_controller.curr_feedforward = 0.0;
+            }
+            MotionCommand::Stop => {
+                pos_controller.mode = ControlMode::Position;
+                pos_controller.pos_setpoint = self.encoder_output.filtered_position;
+                self.clear_feedforward();
+            }
+            MotionCommand::Enable(_) => {}
+        }
+    }
+
+    // force currently being requested, in newtons
+    pub fn force(&self, config: &Config) -> f32 {
+        self.q_req * config.force_constant
+    }
+
+    pub fn clear_feedforward(&mut self) {
+        self.pos_controller.vel_feedforward = 0.0;
+        self.pos_controller.curr_feedforward = 0.0;
+    }
+
+    pub fn update(&mut self, update: &ControllerUpdate, config: &Config) -> CurrentCommand {
+        // encoder output is in terms of mm
+        let encoder_output = update.position.as_ref().unwrap();
+        self.encoder_output = encoder_output.clone();
+
+        let curr_limit_target = if self.hold {
+            config.curr_limit.min(config.hold_curr_limit)
+        } else {
+            config.curr_limit
+        };
+        let ramp_step = config.curr_limit * config.motion_sample_time() / config.enable_ramp_time;
+        self.curr_limit = (self.curr_limit + ramp_step).min(curr_limit_target);
+        let curr_limit = self.curr_limit;
+
+        let q = self.pos_controller.update(encoder_output, self.saturated, config);
+        let q = q.max(-curr_limit).min(curr_limit);
 
-        self.dq_currents = dq_currents;
+        self.saturated = q == curr_limit || q == -curr_limit;
         self.q_req = q;
 
-        voltage_request
-            .inv_park_transform(angle)
-            .to_voltage_controller_output(update)
+        CurrentCommand::Current(CurrentSetpoint {
+            d: 0.0,
+            q,
+            angle: self.cal.to_angle(encoder_output.position, config),
+            velocity: self.cal.to_angular_velocity(encoder_output.velocity, config),
+            sample: update.sample,
+        })
     }
 }
\ No newline at end of file
@@ -8,6 +8,10 @@ pub mod svm;
 pub mod state_machine;
 pub mod calibration;
 pub mod open_loop_voltage;
+pub mod manual;
 pub mod foc;
+pub mod current_loop;
 pub mod transforms;
 pub mod pid;
+pub mod trajectory;
+pub mod motion;
@@ -0,0 +1,121 @@
+// direct control of the output stage for board bring-up, to check each half bridge, the shunt polarity and the phase
+// order. the host writes `ManualController::output` through the remote tree, and it turns itself off again after
+// `Config::manual_max_time`
+use config::Config;
+use crate::current_loop::CurrentCommand;
+use crate::state_machine::VoltageControllerOutput;
+use remote_obj::*;
+use bincode::{Encode, Decode};
+#[allow(unused_imports)]
+use micromath::F32Ext;
+
+#[derive(Debug, Clone, Copy, PartialEq, RemoteGetter, RemoteSetter, Encode, Decode)]
+#[remote(derive(Encode, Decode, Debug))]
+pub enum ManualOutput {
+    Off,
+    /// alpha and beta in units of duty cycle, through the SVM like the open loop calibration
+    AlphaBeta([f32; 2]),
+    /// duty cycle of each half bridge from 0 to 1, straight to the timer
+    Phases([f32; 3]),
+}
+
+#[derive(Debug, RemoteGetter, RemoteSetter)]
+#[remote(derive(Encode, Decode, Debug))]
+pub struct ManualController {
+    pub output: ManualOutput,
+    elapsed: f32, // in seconds, since the output was turned on
+}
+
+impl ManualController {
+    pub fn new() -> ManualController {
+        ManualController {
+            output: ManualOutput::Off,
+            elapsed: 0.0,
+        }
+    }
+
+    // whatever was set before the output went idle is dropped, so it doesn't come back on by itself
+    pub fn engage(&mut self) {
+        self.output = ManualOutput::Off;
+    }
+
+    pub fn update(&mut self, config: &Config) -> CurrentCommand {
+        if self.output == ManualOutput::Off {
+            self.elapsed = 0.0;
+            return CurrentCommand::Idle(false);
+        }
+
+        self.elapsed += config.motion_sample_time();
+        if self.elapsed > config.manual_max_time {
+            self.output = ManualOutput::Off;
+            return CurrentCommand::Idle(false);
+        }
+
+        match self.output {
+            ManualOutput::Off => CurrentCommand::Idle(false),
+            ManualOutput::AlphaBeta([alpha, beta]) => {
+                if !alpha.is_finite() || !beta.is_finite() {
+                    return CurrentCommand::Idle(false);
+                }
+                let magnitude = (alpha * alpha + beta * beta).sqrt();
+                let scale = if magnitude > config.manual_max_duty { config.manual_max_duty / magnitude } else { 1.0 };
+                CurrentCommand::Voltage(VoltageControllerOutput {
+                    driver_enable: true,
+                    alpha: alpha * scale,
+                    beta: beta * scale,
+                })
+            }
+            ManualOutput::Phases(duty) => {
+                if !duty.iter().all(|d| d.is_finite()) {
+                    return CurrentCommand::Idle(false);
+                }
+                // only the differences between the phases drive current, so those are what's limited
+                let duty = duty.map(|d| d.clamp(0.0, 1.0));
+                let min = duty.iter().fold(1.0f32, |a, &d| a.min(d));
+                CurrentCommand::Duty(duty.map(|d| d.min(min + config.manual_max_duty)))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_limits() {
+        let config = Config::new();
+        let mut manual = ManualController::new();
+        assert_eq!(manual.update(&config), CurrentCommand::Idle(false));
+
+        manual.output = ManualOutput::AlphaBeta([1.0, 0.0]);
+        match manual.update(&config) {
+            // micromath's sqrt is only approximate
+            CurrentCommand::Voltage(output) => assert!((output.alpha / config.manual_max_duty - 1.0).abs() < 0.01),
+            command => panic!("{:?}", command),
+        }
+
+        manual.output = ManualOutput::Phases([0.5, 0.0, 0.02]);
+        assert_eq!(manual.update(&config), CurrentCommand::Duty([config.manual_max_duty, 0.0, 0.02]));
+
+        manual.output = ManualOutput::AlphaBeta([f32::NAN, 0.0]);
+        assert_eq!(manual.update(&config), CurrentCommand::Idle(false));
+    }
+
+    #[test]
+    fn test_timeout() {
+        let config = Config::new();
+        let mut manual = ManualController::new();
+        manual.output = ManualOutput::AlphaBeta([0.01, 0.0]);
+
+        let iterations = (config.manual_max_time / config.motion_sample_time()) as u32;
+        for _ in 0..iterations * 9 / 10 {
+            assert!(matches!(manual.update(&config), CurrentCommand::Voltage(_)));
+        }
+        for _ in 0..iterations / 5 {
+            manual.update(&config);
+        }
+        assert_eq!(manual.output, ManualOutput::Off);
+        assert_eq!(manual.update(&config), CurrentCommand::Idle(false));
+    }
+}
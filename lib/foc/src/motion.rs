@@ -0,0 +1,412 @@
+use config::Config;
+use crate::state_machine::{ControllerEvent, ControllerUpdate, CONTROLLER_EVENTS};
+use crate::trajectory::TrajectoryPoint;
+use remote_obj::*;
+use bincode::{Encode, Decode};
+use heapless::Deque;
+#[allow(unused_imports)]
+use micromath::F32Ext;
+
+pub const MOTION_QUEUE: usize = 16;
+// fraction of its velocity a MoveToForce has to reach before the force is taken as contact
+const CRUISE_FRACTION: f32 = 0.9;
+
+/// the stable interface for commanding the actuator from the host
+#[derive(Encode, Decode, Debug, Clone, Copy, PartialEq)]
+pub enum MotionCommand {
+    /// in mm
+    Position(f32),
+    /// in mm/s
+    Velocity(f32),
+    /// in newtons
+    Force(f32),
+    /// spring and damper around a position, in mm, N/mm and N/(mm/s)
+    Impedance { position: f32, stiffness: f32, damping: f32 },
+    /// drops any trajectory or waypoints, and holds the current position
+    Stop,
+    /// `true` also clears a fault from the current loop, see `ControllerEvent::CurrentFault`
+    Enable(bool),
+}
+
+#[derive(Encode, Decode, Debug, Clone, Copy, PartialEq)]
+pub enum MotionError {
+    /// encoder calibration hasn't finished yet
+    NotCalibrated,
+    Disabled,
+    Fault,
+    /// a trajectory or waypoints are running, send `Stop` first
+    Busy,
+    OutOfRange,
+    NotFinite,
+}
+
+impl MotionCommand {
+    pub fn validate(&self, config: &Config) -> Result<(), MotionError> {
+        let finite = |values: &[f32]| values.iter().all(|x| x.is_finite());
+        let position_ok = |x: f32| x >= config.position_min && x <= config.position_max;
+
+        match *self {
+            MotionCommand::Position(position) => {
+                if !finite(&[position]) {
+                    return Err(MotionError::NotFinite);
+                }
+                if !position_ok(position) {
+                    return Err(MotionError::OutOfRange);
+                }
+            }
+            MotionCommand::Velocity(velocity) => {
+                if !finite(&[velocity]) {
+                    return Err(MotionError::NotFinite);
+                }
+                if velocity.abs() > config.max_velocity {
+                    return Err(MotionError::OutOfRange);
+                }
+            }
+            MotionCommand::Force(force) => {
+                if !finite(&[force]) {
+                    return Err(MotionError::NotFinite);
+                }
+                if (force / config.force_constant).abs() > config.curr_limit {
+                    return Err(MotionError::OutOfRange);
+                }
+            }
+            MotionCommand::Impedance { position, stiffness, damping } => {
+                if !finite(&[position, stiffness, damping]) {
+                    return Err(MotionError::NotFinite);
+                }
+                if !position_ok(position) || stiffness < 0.0 || damping < 0.0 {
+                    return Err(MotionError::OutOfRange);
+                }
+            }
+            MotionCommand::Stop | MotionCommand::Enable(_) => {}
+        }
+        Ok(())
+    }
+}
+
+#[derive(Encode, Decode, Debug, Clone, Copy, PartialEq)]
+pub enum Waypoint {
+    /// trapezoidal move, in mm, mm/s and mm/s^2
+    MoveTo { position: f32, max_velocity: f32, max_accel: f32 },
+    /// hold the current position, in seconds
+    Dwell(f32),
+    /// move at a constant velocity until the force exceeds a threshold, in mm/s and newtons. the force is ignored until
+    /// the velocity is reached or for `Config::settle_time`, as accelerating takes force too. gives up and aborts the
+    /// queue once the position reaches `limit`, in mm
+    MoveToForce { velocity: f32, force: f32, limit: f32 },
+}
+
+impl Waypoint {
+    pub fn validate(&self, config: &Config) -> Result<(), MotionError> {
+        match *self {
+            Waypoint::MoveTo { position, max_velocity, max_accel } => {
+                MotionCommand::Position(position).validate(config)?;
+                MotionCommand::Velocity(max_velocity).validate(config)?;
+                if !max_accel.is_finite() {
+                    return Err(MotionError::NotFinite);
+                }
+                if max_velocity <= 0.0 || max_accel <= 0.0 {
+                    return Err(MotionError::OutOfRange);
+                }
+            }
+            Waypoint::Dwell(time) => {
+                if !time.is_finite() {
+                    return Err(MotionError::NotFinite);
+                }
+                if time < 0.0 {
+                    return Err(MotionError::OutOfRange);
+                }
+            }
+            Waypoint::MoveToForce { velocity, force, limit } => {
+                MotionCommand::Velocity(velocity).validate(config)?;
+                MotionCommand::Force(force).validate(config)?;
+                MotionCommand::Position(limit).validate(config)?;
+                if velocity == 0.0 {
+                    return Err(MotionError::OutOfRange);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Encode, Decode, Debug, Clone, Copy, PartialEq)]
+pub struct QueuedWaypoint {
+    pub id: u32, // chosen by the host, reported back on completion
+    pub waypoint: Waypoint,
+}
+
+#[derive(Encode, Decode, Debug, Clone, PartialEq)]
+pub struct MotionQueueStatus {
+    pub accepted: bool,
+    pub level: u8,
+    pub capacity: u8,
+    pub completed: u32,
+}
+
+#[derive(RemoteGetter, RemoteSetter, Debug)]
+#[remote(derive(Encode, Decode, Debug))]
+pub struct MotionQueue {
+    position: f32, // profile position in mm
+    velocity: f32, // profile velocity in mm/s
+    elapsed: f32, // time spent on the current waypoint in seconds
+    settling: f32, // in seconds, since the profile of the current MoveTo arrived
+    cruising: bool, // the current MoveToForce is up to speed
+    completed: u32,
+    #[remote(skip)]
+    current: Option<QueuedWaypoint>,
+    #[remote(skip)]
+    waypoints: Deque<QueuedWaypoint, MOTION_QUEUE>,
+}
+
+impl MotionQueue {
+    pub fn new() -> MotionQueue {
+        MotionQueue {
+            position: 0.0,
+            velocity: 0.0,
+            elapsed: 0.0,
+            settling: 0.0,
+            cruising: false,
+            completed: 0,
+            current: None,
+            waypoints: Deque::new(),
+        }
+    }
+
+    pub fn push(&mut self, waypoint: QueuedWaypoint, config: &Config) -> bool {
+        waypoint.waypoint.validate(config).is_ok() && self.waypoints.push_back(waypoint).is_ok()
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.current.is_some() || !self.waypoints.is_empty()
+    }
+
+    pub fn status(&self, accepted: bool) -> MotionQueueStatus {
+        MotionQueueStatus {
+            accepted,
+            level: self.waypoints.len() as u8,
+            capacity: self.waypoints.capacity() as u8,
+            completed: self.completed,
+        }
+    }
+
+    // drops the current and all queued waypoints, only the current one is reported
+    pub fn abort(&mut self, events: &mut Deque<ControllerEvent, CONTROLLER_EVENTS>) {
+        if let Some(current) = self.current.take() {
+            let _ = events.push_back(ControllerEvent::WaypointAborted(current.id));
+        } else if let Some(next) = self.waypoints.front() {
+            let _ = events.push_back(ControllerEvent::WaypointAborted(next.id));
+        }
+        self.waypoints.clear();
+    }
+
+    // advances the profile towards `target`, returns true once it's there
+    fn step_towards(&mut self, target: f32, max_velocity: f32, max_accel: f32, dt: f32) -> bool {
+        let distance = target - self.position;
+        let direction = if distance >= 0.0 { 1.0 } else { -1.0 };
+
+        // fastest velocity we can still stop from before reaching the target
+        let stopping_velocity = (2.0 * max_accel * distance.abs()).sqrt();
+        let desired = direction * max_velocity.min(stopping_velocity);
+
+        let max_dv = max_accel * dt;
+        self.velocity += (desired - self.velocity).max(-max_dv).min(max_dv);
+
+        let step = self.velocity * dt;
+        if (distance - step) * distance <= 0.0 {
+            self.position = target;
+            self.velocity = 0.0;
+            true
+        } else {
+            self.position += step;
+            false
+        }
+    }
+
+    // returns the setpoint for this control cycle while there are waypoints to execute. `force` is the force
+    // currently being applied by the position controller
+    pub fn update(&mut self, update: &ControllerUpdate, force: f32, config: &Config,
+                  events: &mut Deque<ControllerEvent, CONTROLLER_EVENTS>) -> Option<TrajectoryPoint> {
+        let dt = config.motion_sample_time();
+        let output = update.position.as_ref()?;
+        let actual = output.filtered_position;
+
+        if self.current.is_none() {
+            self.current = self.waypoints.pop_front();
+            self.elapsed = 0.0;
+            self.settling = 0.0;
+            self.cruising = false;
+
+            if self.current.is_none() {
+                // start the next profile from wherever we end up
+                self.position = actual;
+                self.velocity = 0.0;
+                return None;
+            }
+        }
+        let current = self.current.unwrap();
+        self.elapsed += dt;
+
+        let done = match current.waypoint {
+            Waypoint::MoveTo { position, max_velocity, max_accel } => {
+                let arrived = self.step_towards(position, max_velocity, max_accel, dt);
+                let settled = (actual - position).abs() < config.settle_tolerance;
+                if arrived && !settled {
+                    self.settling += dt;
+                    if self.settling > config.waypoint_timeout {
+                        // blocked or pushed away, the waypoints after this one expected to start from here
+                        self.current = None;
+                        self.waypoints.clear();
+                        let _ = events.push_back(ControllerEvent::WaypointTimedOut(current.id));
+                    }
+                }
+                arrived && settled
+            }
+            Waypoint::Dwell(time) => {
+                self.velocity = 0.0;
+                self.elapsed >= time
+            }
+            Waypoint::MoveToForce { velocity, force: threshold, limit } => {
+                let limit = limit.max(config.position_min).min(config.position_max);
+                self.cruising |= output.velocity * velocity.signum() >= CRUISE_FRACTION * velocity.abs() ||
+                    self.elapsed >= config.settle_time;
+                if self.cruising && force.abs() >= threshold.abs() {
+                    // stay where contact was made instead of pushing further
+                    self.position = actual;
+                    self.velocity = 0.0;
+                    true
+                } else {
+                    self.velocity = velocity;
+                    self.position += velocity * dt;
+
+                    if (limit - self.position) * velocity <= 0.0 {
+                        // no contact within the allowed travel, the waypoints after this one expected there to be
+                        self.position = limit;
+                        self.velocity = 0.0;
+                        self.abort(events);
+                        return Some(TrajectoryPoint {
+                            duration: dt,
+                            position: self.position,
+                            velocity: 0.0,
+                            force: 0.0,
+                        });
+                    }
+                    false
+                }
+            }
+        };
+
+        if done {
+            self.completed += 1;
+            self.current = None;
+            let _ = events.push_back(ControllerEvent::WaypointDone(current.id));
+        }
+
+        Some(TrajectoryPoint {
+            duration: dt,
+            position: self.position,
+            velocity: self.velocity,
+            force: 0.0,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transforms::PhaseCurrents;
+    use encoder::EncoderOutput;
+
+    fn update() -> ControllerUpdate {
+        ControllerUpdate {
+            phase_currents: PhaseCurrents { u: 0.0, v: 0.0, w: 0.0 },
+            bus_voltage: 24.0,
+            position: Some(EncoderOutput::default()),
+            sample: 0,
+            fault: None,
+        }
+    }
+
+    #[test]
+    fn test_push_validates() {
+        let config = Config::new();
+        let mut queue = MotionQueue::new();
+        let queued = |waypoint| QueuedWaypoint { id: 1, waypoint };
+
+        assert!(!queue.push(queued(Waypoint::MoveTo { position: f32::NAN, max_velocity: 10.0, max_accel: 100.0 }), &config));
+        assert!(!queue.push(queued(Waypoint::MoveTo { position: config.position_max + 1.0, max_velocity: 10.0, max_accel: 100.0 }), &config));
+        assert!(!queue.push(queued(Waypoint::MoveTo { position: 0.0, max_velocity: 10.0, max_accel: 0.0 }), &config));
+        assert!(!queue.push(queued(Waypoint::Dwell(f32::INFINITY)), &config));
+        assert!(!queue.push(queued(Waypoint::Dwell(-1.0)), &config));
+        assert!(!queue.push(queued(Waypoint::MoveToForce { velocity: 10.0, force: 1.0, limit: f32::NAN }), &config));
+        assert!(!queue.is_active());
+
+        assert!(queue.push(queued(Waypoint::MoveTo { position: 1.0, max_velocity: 10.0, max_accel: 100.0 }), &config));
+        assert!(queue.push(queued(Waypoint::Dwell(0.1)), &config));
+    }
+
+    #[test]
+    fn test_move_to_force_limit() {
+        let config = Config::new();
+        let mut queue = MotionQueue::new();
+        let mut events = Deque::new();
+
+        let waypoint = Waypoint::MoveToForce { velocity: 10.0, force: 1.0, limit: 1.0 };
+        assert!(queue.push(QueuedWaypoint { id: 1, waypoint }, &config));
+        assert!(queue.push(QueuedWaypoint { id: 2, waypoint: Waypoint::Dwell(0.1) }, &config));
+
+        // no force ever builds up, so the move gives up at the limit
+        let mut last = None;
+        for _ in 0..(0.2 / config.motion_sample_time()) as usize {
+            last = queue.update(&update(), 0.0, &config, &mut events).or(last);
+        }
+
+        assert_eq!(last.unwrap().position, 1.0);
+        assert_eq!(events.pop_front(), Some(ControllerEvent::WaypointAborted(1)));
+        assert_eq!(events.pop_front(), None);
+        assert!(!queue.is_active());
+    }
+
+    #[test]
+    fn test_move_to_force_ignores_acceleration() {
+        let config = Config::new();
+        let mut queue = MotionQueue::new();
+        let mut events = Deque::new();
+        let waypoint = Waypoint::MoveToForce { velocity: 10.0, force: 1.0, limit: 10.0 };
+        assert!(queue.push(QueuedWaypoint { id: 1, waypoint }, &config));
+
+        // the force it takes to get going isn't contact
+        let mut moving = update();
+        for _ in 0..10 {
+            queue.update(&moving, 5.0, &config, &mut events);
+        }
+        assert_eq!(events.pop_front(), None);
+
+        moving.position.as_mut().unwrap().velocity = 10.0;
+        queue.update(&moving, 0.0, &config, &mut events);
+        queue.update(&moving, 5.0, &config, &mut events);
+        assert_eq!(events.pop_front(), Some(ControllerEvent::WaypointDone(1)));
+    }
+
+    #[test]
+    fn test_move_to_timeout() {
+        let config = Config::new();
+        let mut queue = MotionQueue::new();
+        let mut events = Deque::new();
+        let waypoint = Waypoint::MoveTo { position: 1.0, max_velocity: 10.0, max_accel: 100.0 };
+        assert!(queue.push(QueuedWaypoint { id: 1, waypoint }, &config));
+        assert!(queue.push(QueuedWaypoint { id: 2, waypoint: Waypoint::Dwell(0.1) }, &config));
+
+        // the motor never moves. the profile takes about 0.2 s, the timeout only starts after it
+        for _ in 0..(config.waypoint_timeout / config.motion_sample_time()) as usize {
+            queue.update(&update(), 0.0, &config, &mut events);
+        }
+        assert_eq!(events.pop_front(), None);
+        for _ in 0..(0.3 / config.motion_sample_time()) as usize {
+            queue.update(&update(), 0.0, &config, &mut events);
+        }
+        assert_eq!(events.pop_front(), Some(ControllerEvent::WaypointTimedOut(1)));
+        assert_eq!(events.pop_front(), None);
+        assert!(!queue.is_active());
+    }
+}
@@ -1,9 +1,8 @@
-use config::Config;
+use config::{Config, OpenLoopMode};
+use crate::current_loop::{CurrentCommand, CurrentSetpoint};
 use crate::state_machine::{ControllerUpdate, VoltageControllerOutput};
 use remote_obj::*;
 use bincode::{Encode, Decode};
-#[allow(unused_imports)]
-use micromath::F32Ext;
 
 #[derive(Debug, RemoteGetter, RemoteSetter)]
 #[remote(derive(Encode, Decode, Debug))]
@@ -23,30 +22,43 @@ impl OpenLoopVoltageController {
         self.position / core::f32::consts::TAU * config.motor_len_per_cycle
     }
 
-    // units of velocity_req is electrical radians per controller timestep
-    pub fn process_velocity(&mut self, velocity_req: f32, update: &ControllerUpdate, config: &Config) -> VoltageControllerOutput {
-        let output = self.process_position(self.position, &update, &config);
-        self.position += velocity_req;
+    // units of velocity_req is electrical radians per second
+    pub fn process_velocity(&mut self, velocity_req: f32, update: &ControllerUpdate, config: &Config) -> CurrentCommand {
+        let output = self.output(velocity_req, update, config);
+        self.position += velocity_req * config.motion_sample_time();
 
         output
     }
 
-    pub fn process_position(&mut self, position_req: f32, update: &ControllerUpdate, config: &Config) -> VoltageControllerOutput {
-        let voltage = update.bus_voltage;
-        let request_duty = config.open_loop_voltage / voltage;
-
-        let s = position_req.sin();
-        let c = position_req.cos();
-
-        let alpha = s * request_duty;
-        let beta = c * request_duty;
-
+    pub fn process_position(&mut self, position_req: f32, update: &ControllerUpdate, config: &Config) -> CurrentCommand {
         self.position = position_req;
+        self.output(0.0, update, config)
+    }
+
+    fn output(&self, velocity: f32, update: &ControllerUpdate, config: &Config) -> CurrentCommand {
+        match config.open_loop_mode {
+            OpenLoopMode::Voltage => {
+                let request_duty = config.open_loop_voltage / update.bus_voltage;
+                let (s, c) = fastmath::sincos(self.position);
 
-        VoltageControllerOutput {
-            driver_enable: true,
-            alpha,
-            beta
+                CurrentCommand::Voltage(VoltageControllerOutput {
+                    driver_enable: true,
+                    alpha: s * request_duty,
+                    beta: c * request_duty,
+                })
+            }
+            // along the d axis like the voltage above, so the rotor lines up the same way in both modes. measured
+            // currents have the opposite sign to the voltage driving them, hence the negative request. the current
+            // loop keeps turning the angle between motion loop iterations
+            OpenLoopMode::Current => {
+                CurrentCommand::Current(CurrentSetpoint {
+                    d: -config.open_loop_current,
+                    q: 0.0,
+                    angle: self.position,
+                    velocity,
+                    sample: update.sample,
+                })
+            }
         }
     }
-}
\ No newline at end of file
+}
@@ -1,9 +1,9 @@
-use config::Config;
+use crate::current_loop::CurrentParams;
 use crate::transforms::{DQCurrents, DQVoltages};
 use remote_obj::*;
 use bincode::{Encode, Decode};
 
-#[derive(Debug, RemoteGetter, RemoteSetter)]
+#[derive(Debug, Clone, RemoteGetter, RemoteSetter)]
 #[remote(derive(Encode, Decode, Debug))]
 pub struct PController {
     pub k_p: f32,
@@ -19,10 +19,10 @@ impl PController {
     }
 }
 
-#[derive(Debug, RemoteGetter, RemoteSetter)]
+#[derive(Debug, Clone, RemoteGetter, RemoteSetter)]
 #[remote(derive(Encode, Decode, Debug))]
 pub struct PIController {
-    pub k_i: f32,
+    pub k_i: f32, // per sample, the integrator is a plain sum
     pub i_error: f32,
     pub p_controller: PController,
 }
@@ -42,9 +42,18 @@ impl PIController {
         }
         self.p_controller.update(error) + self.k_i * self.i_error
     }
+
+    // sets the integrator so that the output is `output` with zero error
+    pub fn preload(&mut self, output: f32) {
+        if self.k_i != 0.0 {
+            self.i_error = output / self.k_i;
+        } else {
+            self.i_error = 0.0;
+        }
+    }
 }
 
-#[derive(Debug, RemoteGetter, RemoteSetter)]
+#[derive(Debug, Clone, RemoteGetter, RemoteSetter)]
 #[remote(derive(Encode, Decode, Debug))]
 pub struct DQCurrentController {
     d_controller: PIController,
@@ -52,25 +61,34 @@ pub struct DQCurrentController {
 }
 
 impl DQCurrentController {
-    pub fn new(config: &Config) -> DQCurrentController {
-        let k_p = config.current_controller_k_p;
-        let k_i = config.current_controller_k_i;
-
+    pub fn new(params: &CurrentParams) -> DQCurrentController {
         DQCurrentController {
-            d_controller: PIController::new(k_i, k_p),
-            q_controller: PIController::new(k_i, k_p),
+            d_controller: PIController::new(params.k_i, params.k_p),
+            q_controller: PIController::new(params.k_i, params.k_p),
         }
     }
 
-    pub fn update(&mut self, current_inputs: &DQCurrents, current_requests: &DQCurrents, config: &Config) -> DQVoltages {
-        self.d_controller.k_i = config.current_controller_k_i;
-        self.q_controller.k_i = config.current_controller_k_i;
-        self.d_controller.p_controller.k_p = config.current_controller_k_p;
-        self.q_controller.p_controller.k_p = config.current_controller_k_p;
+    fn update_gains(&mut self, params: &CurrentParams) {
+        self.d_controller.k_i = params.k_i;
+        self.q_controller.k_i = params.k_i;
+        self.d_controller.p_controller.k_p = params.k_p;
+        self.q_controller.p_controller.k_p = params.k_p;
+    }
+
+    pub fn update(&mut self, current_inputs: &DQCurrents, current_requests: &DQCurrents, params: &CurrentParams) -> DQVoltages {
+        self.update_gains(params);
 
         DQVoltages {
             d: -self.d_controller.update(-(current_inputs.d - current_requests.d), false),
             q: -self.q_controller.update(-(current_inputs.q - current_requests.q), false),
         }
     }
+
+    // sets the integrators so the output starts at `voltages` when the current error is zero
+    pub fn preload(&mut self, voltages: &DQVoltages, params: &CurrentParams) {
+        self.update_gains(params);
+
+        self.d_controller.preload(-voltages.d);
+        self.q_controller.preload(-voltages.q);
+    }
 }
\ No newline at end of file
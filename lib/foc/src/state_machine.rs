@@ -1,12 +1,21 @@
-use config::Config;
-use crate::svm::IterativeSVM;
-use crate::calibration::EncoderCalibrationController;
+use config::{Config, IdleMode, LINEARITY_BINS};
+use crate::calibration::{CalibrationBlob, CalibrationError, CalibrationQuality, EncoderCalibrationController, EncoderCalibrationState};
+use crate::current_loop::{CurrentCommand, CurrentFault};
 use crate::foc::FieldOrientedControl;
+use crate::manual::ManualController;
 use crate::transforms::PhaseCurrents;
+use crate::trajectory::{TrajectoryBuffer, TrajectoryPoint};
+use crate::motion::{MotionCommand, MotionError, MotionQueue, MotionQueueStatus, QueuedWaypoint};
+use heapless::Deque;
 use remote_obj::*;
 use bincode::{Encode, Decode};
-use encoder::EncoderOutput;
+use encoder::{EncoderOutput, EncoderState};
+use encoder::normalizer::Normalizer;
+use encoder::compensation::Compensation;
+use encoder::health::EncoderHealth;
 
+#[derive(RemoteGetter, RemoteSetter, Debug, Clone, PartialEq)]
+#[remote(derive(Encode, Decode, Debug))]
 pub struct VoltageControllerOutput {
     pub driver_enable: bool,
     pub alpha: f32, // units of duty cycle
@@ -18,14 +27,16 @@ pub struct VoltageControllerOutput {
 pub enum VoltageController {
     Cal(EncoderCalibrationController),
     Foc(FieldOrientedControl),
+    /// output set directly by the host, for board bring-up. only left by recalibrating
+    Manual(ManualController),
 }
 
 impl VoltageController {
-    pub fn update(&mut self, update: &ControllerUpdate, config: &Config) -> VoltageControllerOutput {
+    pub fn update(&mut self, update: &ControllerUpdate, config: &Config) -> CurrentCommand {
         match self {
             VoltageController::Cal(cal) => {
-                if cal.is_done() {
-                    self.enter_foc(config)
+                if cal.is_done() && update.position.is_some() {
+                    self.enter_foc(update, config)
                 }
             },
             _ => {}
@@ -38,49 +49,600 @@ impl VoltageController {
             VoltageController::Foc(foc) => {
                 foc.update(update, config)
             }
+            VoltageController::Manual(manual) => {
+                manual.update(config)
+            }
+        }
+    }
+
+    pub fn set_hold(&mut self, hold: bool) {
+        match self {
+            VoltageController::Foc(foc) => {
+                foc.set_hold(hold)
+            }
+            _ => {}
+        }
+    }
+
+    pub fn hold_position(&mut self, update: &ControllerUpdate) {
+        match self {
+            VoltageController::Foc(foc) => {
+                foc.hold_position(update)
+            }
+            _ => {}
+        }
+    }
+
+    pub fn command(&mut self, command: &MotionCommand, config: &Config) -> Result<(), MotionError> {
+        match self {
+            VoltageController::Foc(foc) => {
+                foc.command(command, config);
+                Ok(())
+            }
+            _ => Err(MotionError::NotCalibrated),
         }
     }
 
-    pub fn enter_foc(&mut self, config: &Config) {
+    pub fn set_setpoint(&mut self, setpoint: Option<TrajectoryPoint>, config: &Config) {
+        match self {
+            VoltageController::Foc(foc) => {
+                match setpoint {
+                    Some(p) => foc.set_setpoint(p.position, p.velocity, p.force / config.force_constant),
+                    None => foc.clear_feedforward(),
+                }
+            }
+            _ => {}
+        }
+    }
+
+    pub fn calibration_quality(&self) -> Option<CalibrationQuality> {
+        match self {
+            VoltageController::Cal(cal) => Some(cal.quality.clone()),
+            VoltageController::Foc(_) | VoltageController::Manual(_) => None,
+        }
+    }
+
+    pub fn take_compensation(&mut self) -> Option<Compensation> {
+        match self {
+            VoltageController::Cal(cal) => cal.take_compensation(),
+            VoltageController::Foc(_) | VoltageController::Manual(_) => None,
+        }
+    }
+
+    pub fn take_linearity(&mut self) -> Option<[f32; LINEARITY_BINS]> {
+        match self {
+            VoltageController::Cal(cal) => cal.take_linearity(),
+            VoltageController::Foc(_) | VoltageController::Manual(_) => None,
+        }
+    }
+
+    pub fn calibration_state(&self) -> Option<EncoderCalibrationState> {
+        match self {
+            VoltageController::Cal(cal) => Some(cal.state.clone()),
+            VoltageController::Foc(_) | VoltageController::Manual(_) => None,
+        }
+    }
+
+    pub fn enter_foc(&mut self, update: &ControllerUpdate, config: &Config) {
         match self {
             VoltageController::Cal(cal) => {
-                let foc = FieldOrientedControl::new(cal.get_calib().unwrap(), config);
+                let mut foc = FieldOrientedControl::new(cal.get_calib().unwrap(), config);
+                foc.engage(update, true, config);
                 *self = VoltageController::Foc(foc);
             }
             _ => {}
         }
     }
+
+    // resume driving the output after it has been idle
+    pub fn engage(&mut self, update: &ControllerUpdate, reset_setpoint: bool, config: &Config) {
+        match self {
+            VoltageController::Foc(foc) => {
+                foc.engage(update, reset_setpoint, config)
+            }
+            VoltageController::Manual(manual) => {
+                manual.engage()
+            }
+            _ => {}
+        }
+    }
+}
+
+pub const CONTROLLER_EVENTS: usize = 8;
+
+/// things the host should be told about without asking
+#[derive(Encode, Decode, Debug, Clone, PartialEq)]
+pub enum ControllerEvent {
+    WaypointDone(u32),
+    /// this waypoint and all the ones queued after it were dropped
+    WaypointAborted(u32),
+    /// this MoveTo didn't settle within `Config::waypoint_timeout`, it and the ones queued after it were dropped
+    WaypointTimedOut(u32),
+    /// the encoder calibration moved on to a new step
+    Calibration(EncoderCalibrationState),
+    /// the sweep or the pitch check before it finished, FOC only starts if it passed
+    CalibrationReport(CalibrationQuality),
+    /// the crosstalk compensation was refitted from the calibration sweep and written to the config
+    CompensationFitted,
+    /// the sweep passed but the compensation couldn't be fitted, the config was left alone
+    CompensationFitFailed,
+    /// the linearity lookup table was rebuilt from the sweep and written to the config, with its largest correction
+    /// in mm
+    LinearityFitted(f32),
+    /// the encoder signals stopped making sense, the output is faulted until the encoder is recalibrated
+    EncoderFault(EncoderHealth),
+    /// the current loop turned the output off, it stays off until `MotionCommand::Enable(true)`
+    CurrentFault(CurrentFault),
+    CalibrationDone,
+    /// the imported calibration didn't apply once the encoder was set up from it, the calibration sweep runs instead
+    CalibrationImportRejected(CalibrationError),
+    /// manual mode took over the output, see `Controller::manual`
+    ManualStarted,
+    /// the motor didn't stop within `Config::stop_timeout` for a recalibration or manual mode, the output was disabled
+    /// instead
+    StopTimedOut,
+    /// a recalibration or manual mode waiting for the motor to stop was cancelled by `Stop` or disabling the output
+    HandoverCancelled,
+}
+
+/// what takes over the output stage once the position loop has stopped the motor
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Handover {
+    Calibration,
+    Manual,
+}
+
+/// why the output stage is or isn't being driven by the voltage controller
+#[derive(Debug, Clone, Copy, PartialEq, RemoteGetter, RemoteSetter)]
+#[remote(derive(Encode, Decode, Debug))]
+pub enum OutputState {
+    Active,
+    Disabled,
+    Fault,
+    Disconnected,
+    MoveDone,
 }
 
 #[derive(RemoteGetter, RemoteSetter, Debug)]
 #[remote(derive(Encode, Decode, Debug))]
 pub struct Controller {
-    svm: IterativeSVM,
     voltage_controller: VoltageController,
+    output_state: OutputState,
+    trajectory: TrajectoryBuffer,
+    motion_queue: MotionQueue,
+    calibration_quality: CalibrationQuality, // from the last calibration sweep
+    encoder_health: EncoderHealth, // a fault once the position loop is running
+    // latched, the first fault from the current loop since the last `MotionCommand::Enable(true)`
+    #[remote(skip)]
+    current_fault: Option<CurrentFault>,
+    #[remote(skip)]
+    events: Deque<ControllerEvent, CONTROLLER_EVENTS>,
+    #[remote(skip)]
+    host_connected: bool,
+    #[remote(skip)]
+    enabled: bool,
+    #[remote(skip)]
+    driving: bool,
+    // the setpoint came from a trajectory or waypoint last update
+    #[remote(skip)]
+    following: bool,
+    // filtered, in mm, from the last update with a position. trajectories have to start from here
+    #[remote(skip)]
+    position: f32,
+    // waiting for the motor to stop before handing the output over, see `recalibrate` and `manual`
+    #[remote(skip)]
+    handover: Option<Handover>,
+    #[remote(skip)]
+    stopping_time: f32, // in seconds, spent waiting for the motor to stop
+    // normalizers from an imported calibration, waiting to be handed to the encoder
+    #[remote(skip)]
+    encoder_import: Option<[Normalizer; 8]>,
+    // freshly fitted compensation, waiting to be written to the config
+    #[remote(skip)]
+    compensation: Option<Compensation>,
+    // same for the linearity lookup table
+    #[remote(skip)]
+    linearity: Option<[f32; LINEARITY_BINS]>,
 }
 
 impl Controller {
-    pub fn new(config: &Config) -> Controller {
-        let cycle_time = config.switching_clock_frequency / config.switching_frequency;
-        let dead_time_cycles = config.cycle_deadtime * config.switching_clock_frequency;
-
+    pub fn new() -> Controller {
         Controller {
-            svm: IterativeSVM::new(dead_time_cycles as u16,
-                                   cycle_time as u16),
             voltage_controller: VoltageController::Cal(EncoderCalibrationController::new()),
+            output_state: OutputState::Active,
+            trajectory: TrajectoryBuffer::new(),
+            motion_queue: MotionQueue::new(),
+            calibration_quality: CalibrationQuality::new(),
+            encoder_health: EncoderHealth::Ok,
+            current_fault: None,
+            events: Deque::new(),
+            host_connected: false,
+            enabled: true,
+            driving: false,
+            following: false,
+            position: 0.0,
+            handover: None,
+            stopping_time: 0.0,
+            encoder_import: None,
+            compensation: None,
+            linearity: None,
         }
     }
 
-    pub fn update(&mut self, update: &ControllerUpdate, config: &Config) -> PWMCommand {
-        let voltage_output = self.voltage_controller.update( update, config);
+    fn get_output_state(&mut self, update: &ControllerUpdate, config: &Config) -> OutputState {
+        if let (Some(fault), None) = (update.fault, self.current_fault) {
+            let _ = self.events.push_back(ControllerEvent::CurrentFault(fault));
+            self.current_fault = Some(fault);
+        }
+        let fault = self.current_fault.is_some();
 
-        let mut command = self.svm.calculate(voltage_output);
-        if update.bus_voltage < config.uvlo {
-            command.driver_enable = false;
+        let encoder_health = update.position.as_ref().map_or(EncoderHealth::Ok, |p| p.health);
+        if encoder_health != self.encoder_health {
+            if encoder_health != EncoderHealth::Ok {
+                let _ = self.events.push_back(ControllerEvent::EncoderFault(encoder_health));
+            }
+            self.encoder_health = encoder_health;
         }
+
+        match &mut self.voltage_controller {
+            VoltageController::Foc(foc) => {
+                // needs to run every cycle to keep track of setpoint changes
+                let move_done = foc.move_done(update, config) &&
+                    !self.trajectory.is_running() && !self.motion_queue.is_active();
+
+                // the position can't be trusted, so neither can anything the position loop does
+                if fault || encoder_health != EncoderHealth::Ok {
+                    OutputState::Fault
+                } else if !self.enabled {
+                    OutputState::Disabled
+                } else if !self.host_connected {
+                    OutputState::Disconnected
+                } else if move_done {
+                    OutputState::MoveDone
+                } else {
+                    OutputState::Active
+                }
+            }
+            // nothing else stops the output if the host goes away while setting it
+            VoltageController::Manual(_) => {
+                if fault {
+                    OutputState::Fault
+                } else if !self.enabled {
+                    OutputState::Disabled
+                } else if !self.host_connected {
+                    OutputState::Disconnected
+                } else {
+                    OutputState::Active
+                }
+            }
+            VoltageController::Cal(_) => {
+                if fault {
+                    OutputState::Fault
+                } else if !self.enabled {
+                    OutputState::Disabled
+                } else {
+                    OutputState::Active
+                }
+            }
+        }
+    }
+
+    /// runs at the motion loop rate, the command is followed by the current loop until the next update
+    pub fn update(&mut self, update: &ControllerUpdate, config: &Config) -> CurrentCommand {
+        if let Some(position) = &update.position {
+            self.position = position.filtered_position;
+        }
+        self.check_import(update);
+        self.check_handover(config);
+        let cal_state = self.voltage_controller.calibration_state();
+        let command = self.update_output(update, config);
+
+        let new_cal_state = self.voltage_controller.calibration_state();
+        let step_changed = match (&cal_state, &new_cal_state) {
+            (Some(a), Some(b)) => core::mem::discriminant(a) != core::mem::discriminant(b),
+            (a, b) => a.is_some() != b.is_some(),
+        };
+        if step_changed {
+            let sweep_finished = cal_state == Some(EncoderCalibrationState::Calib2);
+            let _ = self.events.push_back(match new_cal_state.clone() {
+                Some(state) => ControllerEvent::Calibration(state),
+                None => ControllerEvent::CalibrationDone,
+            });
+
+            // the pitch check can fail the calibration before the sweep
+            if sweep_finished || new_cal_state == Some(EncoderCalibrationState::Failed) {
+                if let Some(quality) = self.voltage_controller.calibration_quality() {
+                    self.calibration_quality = quality.clone();
+                    let _ = self.events.push_back(ControllerEvent::CalibrationReport(quality));
+                }
+            }
+            if sweep_finished && new_cal_state == Some(EncoderCalibrationState::Done1) {
+                self.compensation = self.voltage_controller.take_compensation();
+                let _ = self.events.push_back(match self.compensation {
+                    Some(_) => ControllerEvent::CompensationFitted,
+                    None => ControllerEvent::CompensationFitFailed,
+                });
+                self.linearity = self.voltage_controller.take_linearity();
+                if let Some(lut) = &self.linearity {
+                    let max = lut.iter().fold(0.0, |a: f32, x| x.abs().max(a));
+                    let _ = self.events.push_back(ControllerEvent::LinearityFitted(max));
+                }
+            }
+        }
+
         command
     }
 
+    fn update_output(&mut self, update: &ControllerUpdate, config: &Config) -> CurrentCommand {
+        let output_state = self.get_output_state(update, config);
+
+        let idle_mode = match output_state {
+            OutputState::Active => None,
+            OutputState::Disabled => Some(IdleMode::Coast),
+            // shorting the phases would keep an overcurrent going and brake off a sagging bus, so the driver is always
+            // turned off for those
+            OutputState::Fault if self.current_fault.is_some() => Some(IdleMode::Coast),
+            // the closed loop output can't be trusted during a fault, so brake instead of holding
+            OutputState::Fault => match config.idle_on_fault {
+                IdleMode::Hold => Some(IdleMode::Brake),
+                mode => Some(mode),
+            },
+            OutputState::Disconnected => Some(config.idle_on_disconnect),
+            OutputState::MoveDone => Some(config.idle_on_move_done),
+        };
+        // only the position loop can hold
+        let idle_mode = match (idle_mode, &self.voltage_controller) {
+            (Some(IdleMode::Hold), VoltageController::Cal(_) | VoltageController::Manual(_)) => Some(IdleMode::Brake),
+            (mode, _) => mode,
+        };
+
+        match output_state {
+            OutputState::Disabled | OutputState::Fault | OutputState::Disconnected => {
+                self.trajectory.abort();
+                self.motion_queue.abort(&mut self.events);
+            }
+            _ => {}
+        }
+
+        // streamed trajectories take precedence over queued waypoints
+        let setpoint = match (self.trajectory.update(config), &self.voltage_controller) {
+            (Some(p), _) => Some(p),
+            (None, VoltageController::Foc(foc)) => {
+                self.motion_queue.update(update, foc.force(config), config, &mut self.events)
+            }
+            _ => None,
+        };
+        // a trajectory or waypoint that just finished clears its feedforward, otherwise the setpoint from the last
+        // command is left alone
+        let following = setpoint.is_some();
+        if following || self.following {
+            self.voltage_controller.set_setpoint(setpoint, config);
+        }
+        self.following = following;
+
+        // the host is gone, so stay where we are instead of finishing the last move
+        if output_state == OutputState::Disconnected && self.output_state != OutputState::Disconnected {
+            self.voltage_controller.hold_position(update);
+        }
+
+        let driving = match idle_mode {
+            None | Some(IdleMode::Hold) => true,
+            _ => false,
+        };
+        if driving && !self.driving {
+            // the payload might have moved while the output was off, so don't pull it back unless there's a new setpoint
+            let reset_setpoint = self.output_state != OutputState::MoveDone;
+            self.voltage_controller.engage(update, reset_setpoint, config);
+        }
+        self.driving = driving;
+
+        self.output_state = output_state;
+        self.voltage_controller.set_hold(idle_mode == Some(IdleMode::Hold));
+
+        match idle_mode {
+            Some(IdleMode::Coast) => CurrentCommand::Idle(false),
+            Some(IdleMode::Brake) => CurrentCommand::Idle(true),
+            None | Some(IdleMode::Hold) => self.voltage_controller.update(update, config),
+        }
+    }
+
+    pub fn set_host_connected(&mut self, connected: bool) {
+        self.host_connected = connected;
+    }
+
+    pub fn trajectory(&mut self) -> &mut TrajectoryBuffer {
+        &mut self.trajectory
+    }
+
+    // waypoints can only be queued once the position controller is running
+    pub fn queue_waypoint(&mut self, waypoint: QueuedWaypoint, config: &Config) -> MotionQueueStatus {
+        let accepted = match self.voltage_controller {
+            VoltageController::Foc(_) if self.handover.is_none() => self.motion_queue.push(waypoint, config),
+            _ => false,
+        };
+        self.motion_queue.status(accepted)
+    }
+
+    pub fn clear_waypoints(&mut self) -> MotionQueueStatus {
+        self.motion_queue.abort(&mut self.events);
+        self.motion_queue.status(true)
+    }
+
+    pub fn command(&mut self, command: MotionCommand, config: &Config) -> Result<(), MotionError> {
+        command.validate(config)?;
+
+        match command {
+            MotionCommand::Enable(enabled) => {
+                self.enabled = enabled;
+                if !enabled {
+                    self.cancel_handover();
+                }
+                // the current loop reports the fault again if it's still there
+                if enabled {
+                    self.current_fault = None;
+                }
+                return Ok(());
+            }
+            MotionCommand::Stop => {
+                self.trajectory.stop();
+                self.motion_queue.abort(&mut self.events);
+                self.cancel_handover();
+            }
+            _ => {
+                if !self.enabled {
+                    return Err(MotionError::Disabled);
+                }
+                if self.output_state == OutputState::Fault {
+                    return Err(MotionError::Fault);
+                }
+                if self.trajectory.is_running() || self.motion_queue.is_active() || self.handover.is_some() {
+                    return Err(MotionError::Busy);
+                }
+                // drop the points of a finished trajectory, the command replaces the setpoint it left behind
+                self.trajectory.stop();
+            }
+        }
+
+        self.voltage_controller.command(&command, config)
+    }
+
+    // drops back to the start of the encoder calibration. a running position loop first stops the motor where it is,
+    // the calibration takes over once it has settled and sends `ControllerEvent::Calibration` with `Start`. `Stop` or
+    // disabling the output cancel it while waiting
+    pub fn recalibrate(&mut self, config: &Config) -> Result<(), MotionError> {
+        if !self.enabled {
+            return Err(MotionError::Disabled);
+        }
+        // recalibrating is how an encoder fault gets cleared
+        if self.output_state == OutputState::Fault && self.encoder_health == EncoderHealth::Ok {
+            return Err(MotionError::Fault);
+        }
+
+        self.stop_for(Handover::Calibration, config);
+        Ok(())
+    }
+
+    // hands the output stage to the host, see `ManualController`. like recalibrating this stops the motor first, and
+    // sends `ControllerEvent::ManualStarted` once manual mode has taken over. recalibrating is how it's left again
+    pub fn manual(&mut self, config: &Config) -> Result<(), MotionError> {
+        if !self.enabled {
+            return Err(MotionError::Disabled);
+        }
+        if self.output_state == OutputState::Fault {
+            return Err(MotionError::Fault);
+        }
+
+        self.stop_for(Handover::Manual, config);
+        Ok(())
+    }
+
+    fn stop_for(&mut self, handover: Handover, config: &Config) {
+        self.trajectory.stop();
+        self.motion_queue.abort(&mut self.events);
+        let _ = self.voltage_controller.command(&MotionCommand::Stop, config);
+        self.handover = Some(handover);
+        self.stopping_time = 0.0;
+    }
+
+    fn cancel_handover(&mut self) {
+        if self.handover.take().is_some() {
+            let _ = self.events.push_back(ControllerEvent::HandoverCancelled);
+        }
+    }
+
+    // neither the calibration nor manual mode can take over a moving motor, so wait for the position loop to stop it.
+    // anything else isn't driving the output hard enough to need that. if it doesn't stop in time the output is turned
+    // off, so it coasts to a stop before being driven again
+    fn check_handover(&mut self, config: &Config) {
+        let handover = match self.handover {
+            Some(handover) => handover,
+            None => return,
+        };
+        if let VoltageController::Foc(_) = self.voltage_controller {
+            if self.output_state == OutputState::Active {
+                self.stopping_time += config.motion_sample_time();
+                if self.stopping_time < config.stop_timeout {
+                    return;
+                }
+                self.enabled = false;
+                let _ = self.events.push_back(ControllerEvent::StopTimedOut);
+            }
+        }
+
+        self.handover = None;
+        match handover {
+            Handover::Calibration => {
+                self.voltage_controller = VoltageController::Cal(EncoderCalibrationController::new());
+                let _ = self.events.push_back(ControllerEvent::Calibration(EncoderCalibrationState::Start(0)));
+            }
+            Handover::Manual => {
+                self.voltage_controller = VoltageController::Manual(ManualController::new());
+                let _ = self.events.push_back(ControllerEvent::ManualStarted);
+            }
+        }
+    }
+
+    // skips the calibration sweep, the encoder has to be set up from `take_encoder_import` before FOC starts
+    pub fn import_calibration(&mut self, blob: &CalibrationBlob, config: &Config) -> Result<(), CalibrationError> {
+        blob.check(config)?;
+
+        self.trajectory.stop();
+        self.motion_queue.abort(&mut self.events);
+        self.voltage_controller = VoltageController::Cal(EncoderCalibrationController::imported(blob.calibration));
+        self.encoder_import = Some(blob.normalizers);
+        Ok(())
+    }
+
+    pub fn take_encoder_import(&mut self) -> Option<[Normalizer; 8]> {
+        self.encoder_import.take()
+    }
+
+    // the encoder only knows whether its position is absolute once it has been set up from the imported normalizers,
+    // so the import is checked on the first position after that. if it doesn't hold the calibration sweep runs instead
+    fn check_import(&mut self, update: &ControllerUpdate) {
+        if self.encoder_import.is_some() {
+            return;
+        }
+        if let (VoltageController::Cal(cal), Some(position)) = (&mut self.voltage_controller, &update.position) {
+            if let Err(e) = cal.import_done(position.absolute) {
+                self.voltage_controller = VoltageController::Cal(EncoderCalibrationController::new());
+                let _ = self.events.push_back(ControllerEvent::CalibrationImportRejected(e));
+            }
+        }
+    }
+
+    pub fn take_compensation(&mut self) -> Option<Compensation> {
+        self.compensation.take()
+    }
+
+    pub fn take_linearity(&mut self) -> Option<[f32; LINEARITY_BINS]> {
+        self.linearity.take()
+    }
+
+    pub fn export_calibration(&self, encoder: &EncoderState, config: &Config) -> Result<CalibrationBlob, CalibrationError> {
+        match (&self.voltage_controller, encoder.normalizers()) {
+            (VoltageController::Foc(foc), Some(normalizers)) => Ok(CalibrationBlob::new(normalizers, foc.calibration(), config)),
+            _ => Err(CalibrationError::NotCalibrated),
+        }
+    }
+
+    pub fn pop_event(&mut self) -> Option<ControllerEvent> {
+        self.events.pop_front()
+    }
+
+    // trajectories can only be run once the position controller is running, and from close to where the motor is
+    pub fn start_trajectory(&mut self, config: &Config) -> bool {
+        match self.voltage_controller {
+            VoltageController::Foc(_) if self.handover.is_none() => self.trajectory.start(self.position, config),
+            _ => false,
+        }
+    }
+
+    // once disabled the output stays off until the host enables it again
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
     pub fn encoder_ready(&self) -> bool {
         match &self.voltage_controller {
             VoltageController::Cal(c) => {
@@ -89,6 +651,10 @@ impl Controller {
             VoltageController::Foc(_) => {
                 true
             }
+            // the encoder calibration is thrown away, the phases might not be wired the same way afterwards
+            VoltageController::Manual(_) => {
+                false
+            }
         }
     }
 }
@@ -96,12 +662,24 @@ impl Controller {
 #[derive(Debug, Clone, PartialEq)]
 pub struct PWMCommand {
     pub driver_enable: bool,
+    /// only used when the driver is disabled, enables the driver with all phases low to short them together
+    pub brake: bool,
     pub u_duty: u16,
     pub v_duty: u16,
     pub w_duty: u16,
 }
 
 impl PWMCommand {
+    pub fn idle(brake: bool) -> PWMCommand {
+        PWMCommand {
+            driver_enable: false,
+            brake,
+            u_duty: 0,
+            v_duty: 0,
+            w_duty: 0,
+        }
+    }
+
     pub fn to_array(&self) -> [u16; 3] {
         [self.u_duty, self.v_duty, self.w_duty]
     }
@@ -114,4 +692,176 @@ pub struct ControllerUpdate {
     pub bus_voltage: f32,
     #[remote(skip)]
     pub position: Option<EncoderOutput>,
-}
\ No newline at end of file
+    pub sample: u32, // current loop iteration the ADC values are from
+    // from `CurrentLoop::take_fault`, the current loop has already turned the output off
+    #[remote(skip)]
+    pub fault: Option<CurrentFault>,
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::calibration::EncoderCalibration;
+    use std::vec::Vec;
+
+    fn update() -> ControllerUpdate {
+        ControllerUpdate {
+            phase_currents: PhaseCurrents { u: 0.0, v: 0.0, w: 0.0 },
+            bus_voltage: 24.0,
+            position: Some(EncoderOutput::default()),
+            sample: 0,
+            fault: None,
+        }
+    }
+
+    fn running_controller(config: &Config) -> Controller {
+        let mut controller = Controller::new();
+        controller.voltage_controller = VoltageController::Foc(FieldOrientedControl::new(EncoderCalibration::new(0.0), config));
+        controller.set_host_connected(true);
+        controller.update(&update(), config);
+        controller
+    }
+
+    fn q(command: CurrentCommand) -> f32 {
+        match command {
+            CurrentCommand::Current(setpoint) => setpoint.q,
+            command => panic!("{:?}", command),
+        }
+    }
+
+    #[test]
+    fn test_command_survives_updates() {
+        let config = Config::new();
+
+        for command in [MotionCommand::Velocity(10.0), MotionCommand::Force(1.0)] {
+            let mut controller = running_controller(&config);
+            controller.command(command, &config).unwrap();
+            for _ in 0..10 {
+                let q = q(controller.update(&update(), &config));
+                assert!(q != 0.0, "{:?}", command);
+            }
+        }
+    }
+
+    #[test]
+    fn test_current_fault_latches() {
+        let mut config = Config::new();
+        config.idle_on_fault = IdleMode::Brake;
+        let mut controller = running_controller(&config);
+        controller.command(MotionCommand::Velocity(10.0), &config).unwrap();
+
+        let mut faulted = update();
+        faulted.fault = Some(CurrentFault::Overcurrent);
+        assert_eq!(controller.update(&faulted, &config), CurrentCommand::Idle(false));
+        assert_eq!(controller.pop_event(), Some(ControllerEvent::CurrentFault(CurrentFault::Overcurrent)));
+
+        // stays off, and doesn't brake, once the current is back
+        for _ in 0..10 {
+            assert_eq!(controller.update(&update(), &config), CurrentCommand::Idle(false));
+        }
+        assert_eq!(controller.output_state, OutputState::Fault);
+        assert_eq!(controller.command(MotionCommand::Velocity(10.0), &config), Err(MotionError::Fault));
+
+        controller.command(MotionCommand::Enable(true), &config).unwrap();
+        controller.update(&update(), &config);
+        assert_eq!(controller.output_state, OutputState::Active);
+    }
+
+    #[test]
+    fn test_recalibrate_stops_first() {
+        let config = Config::new();
+        let mut controller = running_controller(&config);
+        controller.command(MotionCommand::Velocity(10.0), &config).unwrap();
+        controller.update(&update(), &config);
+
+        // the position loop stops the motor before the calibration takes over
+        controller.recalibrate(&config).unwrap();
+        controller.update(&update(), &config);
+        assert_eq!(controller.voltage_controller.calibration_state(), None);
+        assert_eq!(controller.command(MotionCommand::Position(1.0), &config), Err(MotionError::Busy));
+
+        let mut events = Vec::new();
+        let mut command = CurrentCommand::Idle(false);
+        for _ in 0..(2.0 * config.settle_time / config.motion_sample_time()) as usize {
+            command = controller.update(&update(), &config);
+            while let Some(event) = controller.pop_event() {
+                events.push(event);
+            }
+            if controller.voltage_controller.calibration_state().is_some() {
+                break;
+            }
+        }
+        assert_eq!(events, [ControllerEvent::Calibration(EncoderCalibrationState::Start(0))]);
+        // and brakes before driving the motor open loop
+        assert_eq!(command, CurrentCommand::Idle(true));
+    }
+
+    #[test]
+    fn test_manual_stops_first() {
+        let config = Config::new();
+        let mut controller = running_controller(&config);
+        controller.command(MotionCommand::Velocity(10.0), &config).unwrap();
+        controller.update(&update(), &config);
+
+        controller.manual(&config).unwrap();
+        controller.update(&update(), &config);
+        assert!(matches!(controller.voltage_controller, VoltageController::Foc(_)));
+
+        let mut events = Vec::new();
+        for _ in 0..(2.0 * config.settle_time / config.motion_sample_time()) as usize {
+            controller.update(&update(), &config);
+            while let Some(event) = controller.pop_event() {
+                events.push(event);
+            }
+            if matches!(controller.voltage_controller, VoltageController::Manual(_)) {
+                break;
+            }
+        }
+        assert_eq!(events, [ControllerEvent::ManualStarted]);
+    }
+
+    #[test]
+    fn test_recalibrate_timeout() {
+        let config = Config::new();
+        let mut controller = running_controller(&config);
+        controller.command(MotionCommand::Velocity(10.0), &config).unwrap();
+        controller.update(&update(), &config);
+        controller.recalibrate(&config).unwrap();
+
+        // pushed away from where it was stopped, so it never settles
+        let mut pushed = update();
+        pushed.position.as_mut().unwrap().filtered_position = 10.0 * config.settle_tolerance;
+        let mut events = Vec::new();
+        for _ in 0..(2.0 * config.stop_timeout / config.motion_sample_time()) as usize {
+            controller.update(&pushed, &config);
+            while let Some(event) = controller.pop_event() {
+                events.push(event);
+            }
+        }
+        assert_eq!(events, [
+            ControllerEvent::StopTimedOut,
+            ControllerEvent::Calibration(EncoderCalibrationState::Start(0)),
+        ]);
+        // coasts until the host enables it again
+        assert!(!controller.is_enabled());
+        assert_eq!(controller.update(&pushed, &config), CurrentCommand::Idle(false));
+    }
+
+    #[test]
+    fn test_recalibrate_cancel() {
+        let config = Config::new();
+        for cancel in [MotionCommand::Stop, MotionCommand::Enable(false)] {
+            let mut controller = running_controller(&config);
+            controller.command(MotionCommand::Velocity(10.0), &config).unwrap();
+            controller.update(&update(), &config);
+            controller.recalibrate(&config).unwrap();
+            controller.command(cancel, &config).unwrap();
+            assert_eq!(controller.pop_event(), Some(ControllerEvent::HandoverCancelled));
+
+            for _ in 0..(2.0 * config.stop_timeout / config.motion_sample_time()) as usize {
+                controller.update(&update(), &config);
+            }
+            assert_eq!(controller.voltage_controller.calibration_state(), None);
+            assert_eq!(controller.pop_event(), None);
+        }
+    }
+}
@@ -140,7 +140,7 @@ fn round(x: f32) -> u16 {
     return (x + 0.5f32) as u16;
 }
 
-#[derive(Debug, RemoteGetter, RemoteSetter)]
+#[derive(Debug, Clone, RemoteGetter, RemoteSetter)]
 #[remote(derive(Encode, Decode, Debug))]
 pub struct IterativeSVM {
     pub residuals: [f32; 3],
@@ -185,11 +185,26 @@ impl IterativeSVM {
 
         PWMCommand {
             driver_enable: result_valid,
+            brake: false,
             u_duty: t_a_rounded + self.dead_time,
             v_duty: t_b_rounded + self.dead_time,
             w_duty: t_c_rounded + self.dead_time
         }
     }
+
+    // duty cycles from 0 to 1 straight to the half bridges, for testing the output stage
+    pub fn duty(&self, duty: [f32; 3]) -> PWMCommand {
+        let max = self.cycle_time.saturating_sub(self.dead_time + 1) as f32;
+        let [u, v, w] = duty.map(|d| round(d.clamp(0.0, 1.0) * max) + self.dead_time);
+
+        PWMCommand {
+            driver_enable: true,
+            brake: false,
+            u_duty: u,
+            v_duty: v,
+            w_duty: w,
+        }
+    }
 }
 
 #[cfg(test)]
@@ -0,0 +1,351 @@
+use config::Config;
+use crate::motion::MotionCommand;
+use remote_obj::*;
+use bincode::{Encode, Decode};
+use heapless::Deque;
+#[allow(unused_imports)]
+use micromath::F32Ext;
+
+pub const TRAJECTORY_POINTS: usize = 64;
+pub const TRAJECTORY_CHUNK: usize = 4;
+
+#[derive(Encode, Decode, Debug, Clone, Copy, PartialEq, Default)]
+pub struct TrajectoryPoint {
+    // in seconds from the previous point, the first point of a trajectory is where it starts. relative so the points
+    // don't lose resolution the longer a trajectory runs
+    pub duration: f32,
+    pub position: f32, // in mm
+    pub velocity: f32, // in mm/s
+    pub force: f32, // in newtons
+}
+
+impl TrajectoryPoint {
+    // cubic hermite interpolation of position and velocity, linear interpolation of force. `time` is in seconds since
+    // this point
+    fn interpolate(&self, next: &TrajectoryPoint, time: f32) -> TrajectoryPoint {
+        let h = next.duration;
+        let s = (time / h).max(0.0).min(1.0);
+        let s2 = s * s;
+        let s3 = s2 * s;
+
+        let position = (2.0 * s3 - 3.0 * s2 + 1.0) * self.position
+            + (s3 - 2.0 * s2 + s) * h * self.velocity
+            + (-2.0 * s3 + 3.0 * s2) * next.position
+            + (s3 - s2) * h * next.velocity;
+
+        let velocity = ((6.0 * s2 - 6.0 * s) * self.position
+            + (3.0 * s2 - 4.0 * s + 1.0) * h * self.velocity
+            + (-6.0 * s2 + 6.0 * s) * next.position
+            + (3.0 * s2 - 2.0 * s) * h * next.velocity) / h;
+
+        TrajectoryPoint {
+            duration: time,
+            position,
+            velocity,
+            force: self.force + s * (next.force - self.force),
+        }
+    }
+
+    fn stopped(&self) -> TrajectoryPoint {
+        TrajectoryPoint {
+            velocity: 0.0,
+            force: 0.0,
+            ..*self
+        }
+    }
+}
+
+/// points are only accepted as a whole chunk, `len` says how many of them are valid
+#[derive(Encode, Decode, Debug, Clone, PartialEq)]
+pub struct TrajectoryChunk {
+    pub len: u8,
+    pub points: [TrajectoryPoint; TRAJECTORY_CHUNK],
+    /// no more points will follow, so running out of points is the end of the trajectory and not an underrun
+    pub last: bool,
+}
+
+#[derive(RemoteGetter, RemoteSetter, Encode, Decode, Debug, Clone, Copy, PartialEq)]
+#[remote(derive(Encode, Decode, Debug))]
+pub enum TrajectoryState {
+    Idle,
+    Running,
+    // ran out of points before the last chunk, the position controller holds the last point received
+    Underrun,
+    // finished the last chunk, the position controller holds the last point
+    Done,
+    // stopped due to a fault or the host disconnecting, the position controller has taken over
+    Aborted,
+}
+
+#[derive(Encode, Decode, Debug, Clone, PartialEq)]
+pub struct TrajectoryStatus {
+    pub accepted: bool,
+    pub state: TrajectoryState,
+    pub level: u16,
+    pub capacity: u16,
+    pub samples: u32, // motion loop iterations since the trajectory was started
+    pub underruns: u32,
+}
+
+#[derive(RemoteGetter, RemoteSetter, Debug)]
+#[remote(derive(Encode, Decode, Debug))]
+pub struct TrajectoryBuffer {
+    pub state: TrajectoryState,
+    samples: u32, // since the trajectory was started
+    segment_time: f32, // in seconds since the front point
+    underruns: u32,
+    #[remote(skip)]
+    last_received: bool,
+    #[remote(skip)]
+    points: Deque<TrajectoryPoint, TRAJECTORY_POINTS>,
+}
+
+impl TrajectoryBuffer {
+    pub fn new() -> TrajectoryBuffer {
+        TrajectoryBuffer {
+            state: TrajectoryState::Idle,
+            samples: 0,
+            segment_time: 0.0,
+            underruns: 0,
+            last_received: false,
+            points: Deque::new(),
+        }
+    }
+
+    // the whole chunk is rejected if any point is outside the limits a motion command would be checked against
+    pub fn push(&mut self, chunk: &TrajectoryChunk, config: &Config) -> bool {
+        let len = chunk.len as usize;
+        if len > TRAJECTORY_CHUNK || self.points.capacity() - self.points.len() < len || self.last_received {
+            return false;
+        }
+
+        // times have to be strictly increasing, including across chunks. the duration of the first point isn't used
+        for (i, point) in chunk.points[..len].iter().enumerate() {
+            let first = i == 0 && self.points.is_empty();
+            let valid = point.duration.is_finite() && (first || point.duration > 0.0) &&
+                MotionCommand::Position(point.position).validate(config).is_ok() &&
+                MotionCommand::Velocity(point.velocity).validate(config).is_ok() &&
+                MotionCommand::Force(point.force).validate(config).is_ok();
+            if !valid {
+                return false;
+            }
+        }
+
+        for point in chunk.points[..len].iter() {
+            self.points.push_back(*point).unwrap();
+        }
+        self.last_received = chunk.last;
+        true
+    }
+
+    // the first point has to be close to `position`, in mm, or the position loop would jump to it
+    pub fn start(&mut self, position: f32, config: &Config) -> bool {
+        let first = match self.points.front() {
+            Some(first) if self.state != TrajectoryState::Running => first,
+            _ => return false,
+        };
+        if !((first.position - position).abs() <= config.trajectory_start_tolerance) {
+            return false;
+        }
+        self.state = TrajectoryState::Running;
+        self.samples = 0;
+        self.segment_time = 0.0;
+        true
+    }
+
+    pub fn stop(&mut self) {
+        self.state = TrajectoryState::Idle;
+        self.samples = 0;
+        self.segment_time = 0.0;
+        self.last_received = false;
+        self.points.clear();
+    }
+
+    pub fn abort(&mut self) {
+        match self.state {
+            TrajectoryState::Idle | TrajectoryState::Aborted => {}
+            _ => {
+                self.state = TrajectoryState::Aborted;
+            }
+        }
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.state == TrajectoryState::Running
+    }
+
+    pub fn status(&self, accepted: bool) -> TrajectoryStatus {
+        TrajectoryStatus {
+            accepted,
+            state: self.state,
+            level: self.points.len() as u16,
+            capacity: self.points.capacity() as u16,
+            samples: self.samples,
+            underruns: self.underruns,
+        }
+    }
+
+    // returns the setpoint for this control cycle, if the trajectory is in control of the position
+    pub fn update(&mut self, config: &Config) -> Option<TrajectoryPoint> {
+        match self.state {
+            TrajectoryState::Running => {
+                self.samples = self.samples.saturating_add(1);
+                self.segment_time += config.motion_sample_time();
+
+                // drop finished segments, the front is always the start of the current segment
+                while let Some(next) = self.points.iter().nth(1) {
+                    if next.duration > self.segment_time {
+                        break;
+                    }
+                    self.segment_time -= next.duration;
+                    self.points.pop_front();
+                }
+
+                let mut points = self.points.iter();
+                match (points.next(), points.next()) {
+                    (Some(p0), Some(p1)) => {
+                        Some(p0.interpolate(p1, self.segment_time))
+                    }
+                    (Some(p0), None) => {
+                        if self.last_received {
+                            self.state = TrajectoryState::Done;
+                        } else {
+                            self.state = TrajectoryState::Underrun;
+                            self.underruns += 1;
+                        }
+                        Some(p0.stopped())
+                    }
+                    _ => {
+                        self.state = TrajectoryState::Underrun;
+                        self.underruns += 1;
+                        None
+                    }
+                }
+            }
+            // the last setpoint handed out was the stopped last point, the position loop keeps holding it from here
+            // and queued waypoints can run
+            TrajectoryState::Underrun | TrajectoryState::Done | TrajectoryState::Idle | TrajectoryState::Aborted => None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(points: &[TrajectoryPoint], last: bool) -> TrajectoryChunk {
+        let mut c = TrajectoryChunk {
+            len: points.len() as u8,
+            points: [TrajectoryPoint::default(); TRAJECTORY_CHUNK],
+            last,
+        };
+        c.points[..points.len()].copy_from_slice(points);
+        c
+    }
+
+    fn point(duration: f32, position: f32, velocity: f32) -> TrajectoryPoint {
+        TrajectoryPoint { duration, position, velocity, force: 0.0 }
+    }
+
+    #[test]
+    fn test_interpolate() {
+        let p0 = point(0.0, 0.0, 10.0);
+        let p1 = point(1.0, 10.0, 10.0);
+
+        for i in 0..=10 {
+            let t = i as f32 / 10.0;
+            let p = p0.interpolate(&p1, t);
+            assert!((p.position - 10.0 * t).abs() < 1e-4, "{:?}", p);
+            assert!((p.velocity - 10.0).abs() < 1e-4, "{:?}", p);
+        }
+    }
+
+    #[test]
+    fn test_underrun() {
+        let config = Config::new();
+        let mut buffer = TrajectoryBuffer::new();
+
+        assert!(buffer.push(&chunk(&[point(0.0, 0.0, 0.0), point(0.01, 1.0, 0.0)], false), &config));
+        assert!(!buffer.push(&chunk(&[point(0.0, 1.0, 0.0)], false), &config));
+        assert!(buffer.start(0.0, &config));
+
+        let mut last = None;
+        for _ in 0..(0.1 / config.motion_sample_time()) as usize {
+            last = buffer.update(&config).or(last);
+        }
+
+        assert_eq!(buffer.state, TrajectoryState::Underrun);
+        assert_eq!(buffer.underruns, 1);
+        assert_eq!(last.unwrap().position, 1.0);
+        assert_eq!(last.unwrap().velocity, 0.0);
+        // the position loop holds it from here
+        assert_eq!(buffer.update(&config), None);
+    }
+
+    #[test]
+    fn test_done() {
+        let config = Config::new();
+        let mut buffer = TrajectoryBuffer::new();
+
+        assert!(buffer.push(&chunk(&[point(0.0, 0.0, 0.0), point(0.01, 1.0, 0.0)], true), &config));
+        assert!(!buffer.push(&chunk(&[point(0.01, 1.0, 0.0)], false), &config));
+        assert!(buffer.start(0.0, &config));
+
+        for _ in 0..(0.1 / config.motion_sample_time()) as usize {
+            buffer.update(&config);
+        }
+
+        assert_eq!(buffer.state, TrajectoryState::Done);
+        assert_eq!(buffer.underruns, 0);
+    }
+
+    #[test]
+    fn test_invalid_points() {
+        let config = Config::new();
+        let mut buffer = TrajectoryBuffer::new();
+
+        // one bad point rejects the whole chunk
+        let outside = config.position_max + 1.0;
+        assert!(!buffer.push(&chunk(&[point(0.0, 0.0, 0.0), point(0.01, outside, 0.0)], false), &config));
+        assert!(!buffer.push(&chunk(&[point(f32::NAN, 0.0, 0.0)], false), &config));
+        assert!(!buffer.push(&chunk(&[point(0.0, f32::NAN, 0.0)], false), &config));
+        assert!(!buffer.push(&chunk(&[point(0.0, 0.0, f32::INFINITY)], false), &config));
+        assert_eq!(buffer.status(false).level, 0);
+
+        assert!(buffer.push(&chunk(&[point(0.0, 0.0, 0.0), point(0.01, 1.0, 0.0)], false), &config));
+    }
+
+    #[test]
+    fn test_start_position() {
+        let config = Config::new();
+        let mut buffer = TrajectoryBuffer::new();
+        assert!(buffer.push(&chunk(&[point(0.0, 5.0, 0.0), point(0.01, 6.0, 0.0)], false), &config));
+
+        assert!(!buffer.start(5.0 + 2.0 * config.trajectory_start_tolerance, &config));
+        assert_eq!(buffer.state, TrajectoryState::Idle);
+        assert!(buffer.start(5.0 + 0.5 * config.trajectory_start_tolerance, &config));
+    }
+
+    #[test]
+    fn test_long_trajectory() {
+        let config = Config::new();
+        let mut buffer = TrajectoryBuffer::new();
+        let dt = config.motion_sample_time() as f64;
+        let hour = (3600.0 / dt) as u32;
+        let ramp = |time: f64| (-90.0 + 0.02 * time) as f32;
+        assert!(buffer.push(&chunk(&[point(0.0, ramp(0.0), 0.02)], false), &config));
+        assert!(buffer.start(ramp(0.0), &config));
+
+        // an hour in, the setpoint is as close to the ramp as at the start
+        let mut points = 0;
+        for i in 1..=hour {
+            if buffer.status(false).level < 3 {
+                points += 1;
+                assert!(buffer.push(&chunk(&[point(0.01, ramp(points as f64 * 0.01), 0.02)], false), &config));
+            }
+            let p = buffer.update(&config).unwrap();
+            assert!((p.position - ramp(i as f64 * dt)).abs() < 1e-4, "{} {} {}", i, p.position, ramp(i as f64 * dt));
+        }
+        assert_eq!(buffer.status(false).samples, hour);
+    }
+}
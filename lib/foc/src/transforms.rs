@@ -1,8 +1,19 @@
 use crate::state_machine::{ControllerUpdate, VoltageControllerOutput};
 use bincode::{Decode, Encode};
 use remote_obj::*;
-#[allow(unused_imports)]
-use micromath::F32Ext;
+/// sin and cos of an electrical angle, worked out once for all the transforms at that angle
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SinCos {
+    pub sin: f32,
+    pub cos: f32,
+}
+
+impl SinCos {
+    pub fn new(angle: f32) -> SinCos {
+        let (sin, cos) = fastmath::sincos(angle);
+        SinCos { sin, cos }
+    }
+}
 
 pub struct AlphaBetaCurrents {
     pub alpha: f32, // units of amps
@@ -60,9 +71,8 @@ pub struct DQCurrents {
 }
 
 impl AlphaBetaCurrents {
-    pub fn park_transform(&self, angle: f32) -> DQCurrents {
-        let s = angle.sin();
-        let c = angle.cos();
+    pub fn park_transform(&self, angle: &SinCos) -> DQCurrents {
+        let (s, c) = (angle.sin, angle.cos);
 
         DQCurrents {
             q: self.alpha * c - self.beta * s,
@@ -76,12 +86,23 @@ pub struct DQVoltages {
     pub q: f32,
 }
 
+#[derive(RemoteGetter, RemoteSetter, Debug, Clone, PartialEq, Default)]
+#[remote(derive(Encode, Decode, Debug))]
 pub struct AlphaBetaVoltages {
     pub alpha: f32, // units of volts
     pub beta: f32,
 }
 
 impl AlphaBetaVoltages {
+    pub fn park_transform(&self, angle: &SinCos) -> DQVoltages {
+        let (s, c) = (angle.sin, angle.cos);
+
+        DQVoltages {
+            q: self.alpha * c - self.beta * s,
+            d: self.beta * c + self.alpha * s,
+        }
+    }
+
     pub fn to_voltage_controller_output(&self, update: &ControllerUpdate) -> VoltageControllerOutput {
         VoltageControllerOutput {
             driver_enable: true,
@@ -92,9 +113,8 @@ impl AlphaBetaVoltages {
 }
 
 impl DQVoltages {
-    pub fn inv_park_transform(&self, angle: f32) -> AlphaBetaVoltages {
-        let s = angle.sin();
-        let c = angle.cos();
+    pub fn inv_park_transform(&self, angle: &SinCos) -> AlphaBetaVoltages {
+        let (s, c) = (angle.sin, angle.cos);
 
         AlphaBetaVoltages {
             alpha: self.q * c + self.d * s,
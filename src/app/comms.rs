@@ -7,6 +7,11 @@ use heapless::{Vec, spsc::{Consumer as SpscConsumer, Producer as SpscProducer}};
 use heapless::spsc::Queue;
 use rtt_target::rprintln;
 use common::{Container, ContainerGetter, DeviceToHost, HostToDevice, ScopePacket, BINCODE_CFG, SCOPE_PROBES};
+use config::{Config, ConfigError};
+use config::storage::StorageError;
+use super::storage::{StorageRequest, StorageResult};
+
+pub const STORAGE_RESULTS: usize = 2;
 
 pub struct ControllerComms<const SEND_BUF: usize, const RECV_BUF: usize> {
     send_p: Producer<'static, SEND_BUF>,
@@ -14,6 +19,16 @@ pub struct ControllerComms<const SEND_BUF: usize, const RECV_BUF: usize> {
     sample_id: u32,
     write_every: u32,
     probes: Vec<ContainerGetter, SCOPE_PROBES>,
+    ticks_since_message: u32,
+    storage_request: Option<StorageRequest>,
+    storage_pending: bool,
+    config_changed: bool,
+    timing_reset: bool,
+    // the ADC trigger timer is only set up at power up, so the config keeps the frequency the loops run at and a
+    // changed one is only saved, to be used from the next power up
+    control_frequency: f32,
+    next_control_frequency: f32,
+    storage_c: SpscConsumer<'static, StorageResult, STORAGE_RESULTS>,
 }
 
 fn encode_and_frame(x: DeviceToHost, buf: &mut [u8]) -> usize {
@@ -30,13 +45,74 @@ fn encode_and_frame(x: DeviceToHost, buf: &mut [u8]) -> usize {
 }
 
 impl<const SEND_BUF: usize, const RECV_BUF: usize> ControllerComms<SEND_BUF, RECV_BUF> {
+    // to be run by the storage task, as it blocks for too long to run in the motion loop
+    pub fn take_storage_request(&mut self) -> Option<StorageRequest> {
+        self.storage_request.take()
+    }
+
+    // set when the host may have changed the config, for anything derived from it that the motion loop keeps
+    pub fn take_config_changed(&mut self) -> bool {
+        core::mem::take(&mut self.config_changed)
+    }
+
+    // the timing is shared with the current loop, so the motion loop does the reset once the tick is done
+    pub fn take_timing_reset(&mut self) -> bool {
+        core::mem::take(&mut self.timing_reset)
+    }
+
+    // flash can only be written while the output isn't being driven, and one operation at a time
+    fn request_storage(&mut self, request: StorageRequest, x: &Container) -> Option<DeviceToHost> {
+        if self.storage_pending || x.controller.is_enabled() {
+            return Some(DeviceToHost::StorageReply(Err(StorageError::Busy)));
+        }
+        self.storage_pending = true;
+        self.storage_request = Some(request);
+        None
+    }
+
+    // checked both as it will be saved and as it will run, with the running control frequency
+    fn apply_config(&mut self, mut config: Config, x: &mut Container) -> Result<(), ConfigError> {
+        config.validate()?;
+        let next_control_frequency = core::mem::replace(&mut config.control_frequency, self.control_frequency);
+        config.validate()?;
+        self.next_control_frequency = next_control_frequency;
+        *x.config = config;
+        Ok(())
+    }
+
+    fn storage_done(&mut self, result: StorageResult, x: &mut Container) -> DeviceToHost {
+        self.storage_pending = false;
+        self.config_changed = true;
+        DeviceToHost::StorageReply(match result {
+            StorageResult::Saved(r) => r,
+            StorageResult::Loaded(r) => r.and_then(|config| {
+                self.apply_config(config, x).map_err(StorageError::Invalid)
+            }),
+            StorageResult::Erased(r) => r.and_then(|_| {
+                self.apply_config(Config::new(), x).map_err(StorageError::Invalid)
+            }),
+        })
+    }
+
     pub fn tick(&mut self, x: &mut Container) -> Result<(), ()> {
         self.sample_id += 1;
+        self.ticks_since_message = self.ticks_since_message.saturating_add(1);
 
-        if let Ok(mut grant) = self.send_p.grant_exact(64) {
+        // large enough for the biggest reply, a framed calibration blob
+        if let Ok(mut grant) = self.send_p.grant_exact(128) {
             match self.recv_c.dequeue() {
-                None => {}
+                None => {
+                    if let Some(result) = self.storage_c.dequeue() {
+                        let reply = self.storage_done(result, x);
+                        let length = encode_and_frame(reply, grant.buf());
+                        grant.commit(length);
+                    } else if let Some(event) = x.controller.pop_event() {
+                        let length = encode_and_frame(DeviceToHost::Event(event), grant.buf());
+                        grant.commit(length);
+                    }
+                }
                 Some(host_command) => {
+                    self.ticks_since_message = 0;
                     match host_command {
                         HostToDevice::AddProbe(p) => {
                             let _ = self.probes.push(p);
@@ -51,7 +127,14 @@ impl<const SEND_BUF: usize, const RECV_BUF: usize> ControllerComms<SEND_BUF, REC
                             grant.commit(length);
                         }
                         HostToDevice::Setter(s) => {
-                            let set_result = DeviceToHost::SetterReply(x.set(s));
+                            self.config_changed = true;
+                            // set on a copy holding the control frequency to be saved, and only kept if it's valid
+                            let running = x.config.clone();
+                            x.config.control_frequency = self.next_control_frequency;
+                            let result = x.set(s);
+                            let config = core::mem::replace(x.config, running);
+                            let result = result.and_then(|_| self.apply_config(config, x).map_err(|_| ()));
+                            let set_result = DeviceToHost::SetterReply(result);
                             let length = encode_and_frame(set_result, grant.buf());
                             grant.commit(length);
                         }
@@ -71,11 +154,94 @@ impl<const SEND_BUF: usize, const RECV_BUF: usize> ControllerComms<SEND_BUF, REC
                             let length = encode_and_frame(remove_done, grant.buf());
                             grant.commit(length);
                         }
+                        HostToDevice::Heartbeat => {}
+                        HostToDevice::TrajectoryPoints(chunk) => {
+                            let trajectory = x.controller.trajectory();
+                            let accepted = trajectory.push(&chunk, x.config);
+                            let status = DeviceToHost::TrajectoryStatus(trajectory.status(accepted));
+                            let length = encode_and_frame(status, grant.buf());
+                            grant.commit(length);
+                        }
+                        HostToDevice::TrajectoryStart => {
+                            let accepted = x.controller.start_trajectory(x.config);
+                            let status = DeviceToHost::TrajectoryStatus(x.controller.trajectory().status(accepted));
+                            let length = encode_and_frame(status, grant.buf());
+                            grant.commit(length);
+                        }
+                        HostToDevice::TrajectoryStop => {
+                            let trajectory = x.controller.trajectory();
+                            trajectory.stop();
+                            let status = DeviceToHost::TrajectoryStatus(trajectory.status(true));
+                            let length = encode_and_frame(status, grant.buf());
+                            grant.commit(length);
+                        }
+                        HostToDevice::QueueWaypoint(waypoint) => {
+                            let status = DeviceToHost::MotionQueueStatus(x.controller.queue_waypoint(waypoint, x.config));
+                            let length = encode_and_frame(status, grant.buf());
+                            grant.commit(length);
+                        }
+                        HostToDevice::ClearWaypoints => {
+                            let status = DeviceToHost::MotionQueueStatus(x.controller.clear_waypoints());
+                            let length = encode_and_frame(status, grant.buf());
+                            grant.commit(length);
+                        }
+                        HostToDevice::Motion(command) => {
+                            let reply = DeviceToHost::MotionReply(x.controller.command(command, x.config));
+                            let length = encode_and_frame(reply, grant.buf());
+                            grant.commit(length);
+                        }
+                        HostToDevice::Recalibrate => {
+                            let reply = DeviceToHost::RecalibrateReply(x.controller.recalibrate(x.config));
+                            let length = encode_and_frame(reply, grant.buf());
+                            grant.commit(length);
+                        }
+                        HostToDevice::Manual => {
+                            let reply = DeviceToHost::ManualReply(x.controller.manual(x.config));
+                            let length = encode_and_frame(reply, grant.buf());
+                            grant.commit(length);
+                        }
+                        HostToDevice::ExportCalibration => {
+                            let reply = DeviceToHost::Calibration(x.controller.export_calibration(x.encoder, x.config));
+                            let length = encode_and_frame(reply, grant.buf());
+                            grant.commit(length);
+                        }
+                        HostToDevice::ImportCalibration(blob) => {
+                            let reply = DeviceToHost::ImportCalibrationReply(x.controller.import_calibration(&blob, x.config));
+                            let length = encode_and_frame(reply, grant.buf());
+                            grant.commit(length);
+                        }
+                        // for storage commands, the reply is sent once the storage task is done unless rejected
+                        HostToDevice::SaveConfig => {
+                            let mut config = x.config.clone();
+                            config.control_frequency = self.next_control_frequency;
+                            if let Some(reply) = self.request_storage(StorageRequest::Save(config), x) {
+                                let length = encode_and_frame(reply, grant.buf());
+                                grant.commit(length);
+                            }
+                        }
+                        HostToDevice::LoadConfig => {
+                            if let Some(reply) = self.request_storage(StorageRequest::Load, x) {
+                                let length = encode_and_frame(reply, grant.buf());
+                                grant.commit(length);
+                            }
+                        }
+                        HostToDevice::FactoryReset => {
+                            if let Some(reply) = self.request_storage(StorageRequest::FactoryReset, x) {
+                                let length = encode_and_frame(reply, grant.buf());
+                                grant.commit(length);
+                            }
+                        }
+                        HostToDevice::ResetTiming => {
+                            self.timing_reset = true;
+                        }
                     }
                 }
             }
         }
 
+        let host_timeout = x.config.host_timeout / x.config.motion_sample_time();
+        x.controller.set_host_connected((self.ticks_since_message as f32) < host_timeout);
+
         if self.sample_id % self.write_every != 0 {
             return Ok(());
         }
@@ -112,7 +278,7 @@ pub struct USBCommunicator<const SEND_BUF: usize, const RECV_BUF: usize> {
 
 impl<const SEND_BUF: usize, const RECV_BUF: usize> USBCommunicator<SEND_BUF, RECV_BUF> {
     pub fn run(&mut self) {
-        let mut buf: Vec<u8, 128> = Vec::new();
+        let mut buf: Vec<u8, 256> = Vec::new();
         let mut codec = framed::bytes::Config::default().to_codec();
 
         loop {
@@ -130,7 +296,7 @@ impl<const SEND_BUF: usize, const RECV_BUF: usize> USBCommunicator<SEND_BUF, REC
                 }
                 Err(_) => {}
             }
-            if (buf.len() + self.cdc.max_packet_size() as usize) < buf.capacity() {
+            if (buf.len() + self.cdc.max_packet_size() as usize) <= buf.capacity() {
                 let packet_buffer = &mut [0; 64];
                 match self.cdc.read_packet(packet_buffer) {
                     Ok(read_length) => {
@@ -140,7 +306,6 @@ impl<const SEND_BUF: usize, const RECV_BUF: usize> USBCommunicator<SEND_BUF, REC
                 };
             }
             if self.recv_p.ready() {
-                let mut consumed_length = 0;
                 let mut prev_packet = 0;
                 for (idx, i) in buf.iter().enumerate() {
                     if !self.recv_p.ready() {
@@ -181,10 +346,12 @@ impl<const SEND_BUF: usize, const RECV_BUF: usize> USBCommunicator<SEND_BUF, REC
                     }
                 }
 
-                if consumed_length == 0 { // something's funky, reset and try again
-                    buf.clear();
-                } else{
+                // keep partial frames around, as frames can be split over multiple usb packets
+                if prev_packet != 0 {
                     buf = Vec::from_slice(&buf[prev_packet..]).unwrap();
+                } else if buf.len() + self.cdc.max_packet_size() as usize > buf.capacity() {
+                    // no frame end in a full buffer, something's funky, reset and try again
+                    buf.clear();
                 }
             }
         }
@@ -194,6 +361,8 @@ impl<const SEND_BUF: usize, const RECV_BUF: usize> USBCommunicator<SEND_BUF, REC
 pub fn get_comms_pair<'a, const SEND_BUF: usize, const RECV_BUF: usize>(
     bbq: &'static mut BBBuffer<SEND_BUF>,
     q: &'static mut Queue<HostToDevice, RECV_BUF>,
+    storage_c: SpscConsumer<'static, StorageResult, STORAGE_RESULTS>,
+    control_frequency: f32,
     cdc: CdcAcmClass<'static, UsbBusType>,
     bus: UsbDevice<'static, UsbBusType>)
     -> (ControllerComms<SEND_BUF, RECV_BUF>, USBCommunicator<SEND_BUF, RECV_BUF>) {
@@ -206,6 +375,14 @@ pub fn get_comms_pair<'a, const SEND_BUF: usize, const RECV_BUF: usize>(
             sample_id: 0,
             write_every: u32::MAX,
             probes: Vec::new(),
+            ticks_since_message: u32::MAX,
+            storage_request: None,
+            storage_pending: false,
+            config_changed: false,
+            timing_reset: false,
+            control_frequency,
+            next_control_frequency: control_frequency,
+            storage_c,
         },
         USBCommunicator {
             cdc,
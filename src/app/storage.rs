@@ -0,0 +1,65 @@
+use stm32f4xx_hal::flash::{FlashExt, LockedFlash};
+use config::Config;
+use config::storage::{self, ConfigStorage, StorageError};
+
+// the last 128K sector, excluded from FLASH in memory.x
+const SECTOR: u8 = 7;
+const SECTOR_OFFSET: usize = 0x6_0000;
+const SECTOR_LEN: usize = 0x2_0000;
+
+pub struct FlashStorage {
+    flash: LockedFlash,
+}
+
+impl FlashStorage {
+    pub fn new(flash: LockedFlash) -> FlashStorage {
+        FlashStorage {
+            flash
+        }
+    }
+}
+
+impl ConfigStorage for FlashStorage {
+    fn read(&mut self, offset: usize, buf: &mut [u8]) -> Result<(), StorageError> {
+        if offset + buf.len() > SECTOR_LEN {
+            return Err(StorageError::Flash);
+        }
+        let start = SECTOR_OFFSET + offset;
+        buf.copy_from_slice(&self.flash.read()[start..start + buf.len()]);
+        Ok(())
+    }
+
+    fn erase(&mut self) -> Result<(), StorageError> {
+        self.flash.unlocked().erase(SECTOR).map_err(|_| StorageError::Flash)
+    }
+
+    fn write(&mut self, offset: usize, data: &[u8]) -> Result<(), StorageError> {
+        if offset + data.len() > SECTOR_LEN {
+            return Err(StorageError::Flash);
+        }
+        self.flash.unlocked().program(SECTOR_OFFSET + offset, data.iter()).map_err(|_| StorageError::Flash)
+    }
+}
+
+// erasing stalls all flash reads, so this runs in its own task while the motor is disabled
+pub enum StorageRequest {
+    Save(Config),
+    Load,
+    FactoryReset,
+}
+
+pub enum StorageResult {
+    Saved(Result<(), StorageError>),
+    Loaded(Result<Config, StorageError>),
+    Erased(Result<(), StorageError>),
+}
+
+impl StorageRequest {
+    pub fn run(self, flash: &mut FlashStorage) -> StorageResult {
+        match self {
+            StorageRequest::Save(config) => StorageResult::Saved(storage::save(flash, &config)),
+            StorageRequest::Load => StorageResult::Loaded(storage::load(flash)),
+            StorageRequest::FactoryReset => StorageResult::Erased(storage::factory_reset(flash)),
+        }
+    }
+}
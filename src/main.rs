@@ -4,7 +4,7 @@
 
 use panic_rtt_target as _;
 
-#[rtic::app(device = stm32f4xx_hal::pac, dispatchers = [EXTI0, EXTI1])]
+#[rtic::app(device = stm32f4xx_hal::pac, dispatchers = [EXTI0, EXTI1, EXTI2])]
 mod app {
     use dwt_systick_monotonic::DwtSystick;
     use dwt_systick_monotonic::fugit::Duration;
@@ -26,9 +26,10 @@ mod app {
     };
 
     use foc::state_machine::{PWMCommand, Controller};
+    use foc::current_loop::CurrentLoop;
     use config::Config;
 
-    use encoder::EncoderState;
+    use encoder::{EncoderState, ellipse};
 
     use heapless::spsc::Queue;
     use bbqueue::BBBuffer;
@@ -38,10 +39,17 @@ mod app {
     use usbd_serial::CdcAcmClass;
 
     use common::*;
+    use common::timing::LoopTiming;
+    use cortex_m::peripheral::DWT;
 
     mod comms;
     use comms::*;
 
+    mod storage;
+    use storage::{FlashStorage, StorageRequest, StorageResult};
+    use stm32f4xx_hal::flash::LockedFlash;
+    use heapless::spsc::Producer;
+
     pub struct MotorOutputBlock {
         u: PwmChannel<TIM1, 0_u8>,
         v: PwmChannel<TIM1, 1_u8>,
@@ -60,7 +68,11 @@ mod app {
                 self.u.set_duty(0);
                 self.v.set_duty(0);
                 self.w.set_duty(0);
-                self.pwm_en.set_low();
+                if pwm_req.brake {
+                    self.pwm_en.set_high();
+                } else {
+                    self.pwm_en.set_low();
+                }
             }
         }
     }
@@ -76,6 +88,9 @@ mod app {
 
     #[shared]
     struct Shared {
+        // commands go in from the motion loop, the current loop runs from it at a higher priority
+        current: CurrentLoop,
+        timing: LoopTiming,
     }
 
     const SEND_BUF: usize = 8192;
@@ -91,6 +106,9 @@ mod app {
         encoder: EncoderState,
         config: Config,
         pwm: MotorOutputBlock,
+        sample: u32, // ADC samples, wrapping, dropped ones included so the current loop keeps time
+        flash: FlashStorage,
+        storage_p: Producer<'static, StorageResult, STORAGE_RESULTS>,
     }
 
     #[init(local = [adc_buffer_: [u16; 16] = [0; 16],
@@ -98,7 +116,8 @@ mod app {
     ep_memory: [u32; 1024] = [0; 1024],
     usb_bus: Option < UsbBusAllocator < UsbBus < USB >> > = None,
     bbq: BBBuffer< SEND_BUF > = BBBuffer::new(),
-    cmd_q: Queue< HostToDevice, RECV_BUF > = Queue::new()])]
+    cmd_q: Queue< HostToDevice, RECV_BUF > = Queue::new(),
+    storage_q: Queue< StorageResult, STORAGE_RESULTS > = Queue::new()])]
     fn init(cx: init::Context) -> (Shared, Local, init::Monotonics) {
         rtt_init_print!();
 
@@ -206,13 +225,23 @@ mod app {
         block!(ctrl_timer.wait()).unwrap();
         usb_pull.set_high();
 
+        let mut flash = FlashStorage::new(LockedFlash::new(device.FLASH));
+        let config = match config::storage::load(&mut flash) {
+            Ok(config) => config,
+            Err(e) => {
+                rprintln!("using default config: {:?}", e);
+                Config::new()
+            }
+        };
+        // the current loop runs once per ADC conversion, so the trigger period sets the control frequency. only read at
+        // power up, a changed control_frequency is saved and used after a restart. `load` checked it's in range
         ctrl_timer
-            .start(Duration::<u32, 1, 2_000_000>::from_ticks(250))
+            .start(Duration::<u32, 1, 2_000_000>::from_ticks((2e6 / config.control_frequency) as u32))
             .unwrap();
         ctrl_timer.listen(Event::Update);
 
         let dma = StreamsTuple::new(device.DMA2);
-        let config = DmaConfig::default()
+        let dma_config = DmaConfig::default()
             .transfer_complete_interrupt(true)
             .memory_increment(true)
             .double_buffer(false);
@@ -317,18 +346,20 @@ mod app {
         );
 
         let mut adc_transfer =
-            Transfer::init_peripheral_to_memory(dma.0, adc, cx.local.adc_buffer2, None, config);
+            Transfer::init_peripheral_to_memory(dma.0, adc, cx.local.adc_buffer2, None, dma_config);
         adc_transfer.start(|_| {});
 
         usb_idle_polling::spawn().ok().unwrap();
         rprintln!("spawned");
 
-        let (p, c) = get_comms_pair(cx.local.bbq, cx.local.cmd_q, serial, usb_dev);
+        let (storage_p, storage_c) = cx.local.storage_q.split();
+        let (p, c) =
+            get_comms_pair(cx.local.bbq, cx.local.cmd_q, storage_c, config.control_frequency, serial, usb_dev);
 
-        let config = Config::new();
-        let controller = Controller::new(&config);
+        let controller = Controller::new();
+        let current = CurrentLoop::new(&config);
         (
-            Shared { },
+            Shared { current, timing: LoopTiming::new() },
             Local {
                 p,
                 c,
@@ -342,7 +373,10 @@ mod app {
                     v: ch_v,
                     w: ch_w,
                     pwm_en: gpioc.pc6.into_push_pull_output()
-                }
+                },
+                sample: 0,
+                flash,
+                storage_p,
             },
             init::Monotonics(mono),
         )
@@ -353,65 +387,143 @@ mod app {
         cx.local.c.run();
     }
 
-    #[task(local = [p, controller, config, pwm, encoder], priority = 2, capacity = 1)]
-    fn control_loop(cx: control_loop::Context, buffer: [u16; 16]) {
-        let control_loop::Context { local } = cx;
-        let control_loop::LocalResources {
+    // synchronized to the ADC and PWM, so only the current controller and SVM run here
+    #[task(local = [pwm], shared = [current, timing], priority = 3, capacity = 1)]
+    fn current_loop(mut cx: current_loop::Context, buffer: [u16; 16], sample: u32) {
+        let start = DWT::cycle_count();
+
+        let update = to_controller_update(&buffer, None, sample);
+        let (pwm_req, motion_divider, svm_cycles) = cx.shared.current.lock(|current_loop| {
+            let modulation = current_loop.control(&update);
+            let svm_start = DWT::cycle_count();
+            let pwm_req = current_loop.modulate(modulation);
+            (pwm_req, current_loop.motion_divider(), DWT::cycle_count().wrapping_sub(svm_start))
+        });
+        cx.local.pwm.set_duty(&pwm_req);
+
+        let overrun = sample % motion_divider == 0 && motion_loop::spawn(buffer, sample).is_err();
+
+        let cycles = DWT::cycle_count().wrapping_sub(start);
+        cx.shared.timing.lock(|timing| {
+            timing.current.record(cycles);
+            timing.svm.record(svm_cycles);
+            if overrun {
+                timing.motion_overruns += 1;
+            }
+        });
+    }
+
+    // the encoder, position loop, trajectories and comms, at a fraction of the current loop rate
+    #[task(local = [p, controller, config, encoder], shared = [current, timing], priority = 2, capacity = 1)]
+    fn motion_loop(mut cx: motion_loop::Context, buffer: [u16; 16], sample: u32) {
+        let start = DWT::cycle_count();
+        let motion_loop::LocalResources {
             p,
             controller,
             config,
             encoder,
-            pwm
-        } = local;
+        } = cx.local;
 
-        let position = encoder.update([
-            buffer[1] as f32,
-            buffer[2] as f32,
-            buffer[3] as f32,
-            buffer[4] as f32,
-            buffer[5] as f32,
-            buffer[6] as f32,
-            buffer[7] as f32,
-            buffer[8] as f32,
-        ], &config);
-
-        let update = to_controller_update(&buffer, position, &config);
-        let mut pwm_req = controller.update(&update, &config);
-
-        if update.phase_currents.max_magnitude() > config.hard_curr_limit {
-            pwm_req.driver_enable = false;
+        let encoder_values = config.encoder_adc.map(|i| buffer.get(i as usize).copied().unwrap_or(0) as f32);
+        let position = encoder.update(encoder_values, &config);
+        let encoder_done = DWT::cycle_count();
+
+        let mut update = to_controller_update(&buffer, position, sample);
+        update.fault = cx.shared.current.lock(|current_loop| current_loop.take_fault());
+        let command = controller.update(&update, &config);
+
+        if let Some(compensation) = controller.take_compensation() {
+            config.comp_matrix = compensation.matrix;
+            config.comp_bias = compensation.bias;
+            if let Some(normalizers) = encoder.normalizers() {
+                ellipse::fit_tracks(config, &compensation.moments, &normalizers);
+            }
+            // after the ellipses, which shift the track angles along with the compensation
+            encoder.update_compensation(&config);
         }
 
-        if controller.encoder_ready() {
-            encoder.calibration_done();
+        if let Some(lut) = controller.take_linearity() {
+            config.linearity_lut = lut;
         }
 
-        pwm.set_duty(&pwm_req);
+        if let Some(normalizers) = controller.take_encoder_import() {
+            encoder.import(normalizers, &config);
+        } else if controller.encoder_ready() {
+            encoder.calibration_done(&config);
+        } else {
+            encoder.restart_calibration();
+        }
+
+        // the lock is only held for the handover, the copy is for the host to look at
+        let current_loop = cx.shared.current.lock(|current_loop| {
+            current_loop.set_command(command, config);
+            current_loop.clone()
+        });
+        let controller_done = DWT::cycle_count();
+
+        let timing = cx.shared.timing.lock(|timing| {
+            timing.encoder.record(encoder_done.wrapping_sub(start));
+            timing.controller.record(controller_done.wrapping_sub(encoder_done));
+            *timing
+        });
 
         let mut container = Container {
             adc: &buffer,
-            pwm: &pwm_req.to_array(),
+            pwm: current_loop.duty(),
             controller,
+            current_loop: &current_loop,
             update: &update,
             encoder,
             config,
+            timing: &timing,
         };
 
         p.tick(&mut container);
+        if p.take_config_changed() {
+            encoder.update_compensation(&config);
+        }
+        let reset_timing = p.take_timing_reset();
+
+        let end = DWT::cycle_count();
+        cx.shared.timing.lock(|timing| {
+            if reset_timing {
+                *timing = LoopTiming::new();
+            }
+            timing.comms.record(end.wrapping_sub(controller_done));
+            timing.motion.record(end.wrapping_sub(start));
+        });
+
+        if let Some(request) = p.take_storage_request() {
+            // only one request is ever outstanding, so this can't be full
+            flash_storage::spawn(request).ok().unwrap();
+        }
     }
 
-    #[task(binds = DMA2_STREAM0, local = [adc_buffer, adc_transfer], priority = 5)]
+    // same priority as the motion loop so it can't be interrupted by it, the motion loop is skipped while flash is
+    // busy instead, which counts as motion overruns
+    #[task(local = [flash, storage_p], priority = 2, capacity = 1)]
+    fn flash_storage(cx: flash_storage::Context, request: StorageRequest) {
+        let result = request.run(cx.local.flash);
+        cx.local.storage_p.enqueue(result).ok().unwrap();
+    }
+
+    #[task(binds = DMA2_STREAM0, local = [adc_buffer, adc_transfer, sample], shared = [timing], priority = 5)]
     fn dma(cx: dma::Context) {
-        let dma::Context { local } = cx;
+        let dma::Context { local, mut shared, .. } = cx;
         let dma::LocalResources {
             adc_buffer,
             adc_transfer,
+            sample,
         } = local;
         let (buffer, _) = adc_transfer
             .next_transfer(adc_buffer.take().unwrap())
             .unwrap();
 
-        control_loop::spawn(buffer.clone()).unwrap();
+        *sample = sample.wrapping_add(1);
+        // the sample is dropped and the current loop keeps going with the next one
+        if current_loop::spawn(buffer.clone(), *sample).is_err() {
+            shared.timing.lock(|timing| timing.current_overruns += 1);
+        }
 
         *adc_buffer = Some(buffer);
 